@@ -2,6 +2,7 @@ use std::cmp::Ordering;
 
 use crate::orderbook_aggregator::{Level, Summary};
 
+#[allow(clippy::upper_case_acronyms)]
 pub enum QuoteType {
     ASKS,
     BIDS,
@@ -11,50 +12,47 @@ pub enum QuoteType {
 pub struct Aggregator {}
 
 impl Aggregator {
-    pub fn aggregate_top(
+    /// Merges the top `n` levels of each summary into a single list sorted
+    /// with the best level first. The merged list is not truncated, so every
+    /// exchange keeps up to `n` levels in it and a subscriber may still pick
+    /// the top levels of a subset of exchanges from it.
+    pub fn aggregate(
         n: usize,
-        summary_a: Option<Summary>,
-        summary_b: Option<Summary>,
+        summaries: &[&Summary],
         quote_type: QuoteType,
     ) -> Vec<Level> {
+        let mut levels = Vec::new();
 
-        let top_n_levels = |summary: Option<Summary>| -> Vec<Level> {
-            match summary {
-                Some(summary) => {
-                    match quote_type {
-                        QuoteType::ASKS => Aggregator::top(n, summary.asks),
-                        QuoteType::BIDS => Aggregator::top(n, summary.bids)
-                    }
-                }
-                _ => Vec::new()
-            }
-        };
+        for summary in summaries {
+            let summary_levels = match quote_type {
+                QuoteType::ASKS => &summary.asks,
+                QuoteType::BIDS => &summary.bids,
+            };
 
-        let a_top_n_levels = top_n_levels(summary_a);
-        let b_top_n_levels = top_n_levels(summary_b);
+            levels.extend(summary_levels.iter().take(n).cloned());
+        }
 
-        let combined_levels = Aggregator::combine_and_sort(
-            a_top_n_levels,
-            b_top_n_levels,
-            quote_type
-        );
+        Aggregator::sort(levels, quote_type)
+    }
 
-        Aggregator::top(n, combined_levels)
+    /// Spread between the best bid and the best ask, or NaN if either side of
+    /// the book is empty.
+    pub fn spread(bids: &[Level], asks: &[Level]) -> f64 {
+        match (bids.first(), asks.first()) {
+            (Some(bid), Some(ask)) => bid.price - ask.price,
+            _ => f64::NAN,
+        }
     }
 
-    /// Combine the arguments `levels_a` and `level_b` and sort them such that
-    /// the best level is at the top. The switch `order` may be used to sort
-    /// ascending (placing highest prices first as required for bids) or
-    /// descending (placing lowest prices first as required for asks) order.
-    /// When sorting larger amount for a price quote is placed before the
-    /// smaller amount such that best quote is at the top.
-    fn combine_and_sort(
-        mut levels_a: Vec<Level>,
-        mut levels_b: Vec<Level>,
+    /// Sort `levels` such that the best level is at the top. The switch
+    /// `quote_type` may be used to sort ascending (placing lowest prices first
+    /// as required for asks) or descending (placing highest prices first as
+    /// required for bids) order. When sorting larger amount for a price quote
+    /// is placed before the smaller amount such that best quote is at the top.
+    fn sort(
+        mut levels: Vec<Level>,
         quote_type: QuoteType,
     ) -> Vec<Level> {
-        levels_a.append(&mut levels_b);
-
         let compare = match quote_type {
             QuoteType::ASKS => |a: &Level, b: &Level| -> Ordering {
                 if a.price == b.price {
@@ -70,9 +68,9 @@ impl Aggregator {
             },
         };
 
-        levels_a.sort_by(compare);
+        levels.sort_by(compare);
 
-        levels_a
+        levels
     }
 
     /// Gets the top `n` elements from `levels`. This function expects `levels`
@@ -85,3 +83,34 @@ impl Aggregator {
         levels
     }
 }
+
+#[test]
+fn test_aggregate_merges_every_summary() {
+    let level = |exchange: &str, price: f64| Level {
+        exchange: String::from(exchange),
+        price,
+        amount: 1.0,
+    };
+    let summary = |exchange: &str, bids: &[f64]| Summary {
+        bids: bids.iter().map(|price| level(exchange, *price)).collect(),
+        ..Summary::default()
+    };
+
+    let binance = summary("Binance", &[10.0, 7.0, 4.0]);
+    let bitstamp = summary("Bitstamp", &[9.0, 8.0]);
+    let kraken = summary("Kraken", &[11.0]);
+
+    let bids = Aggregator::aggregate(
+        2,
+        &[&binance, &bitstamp, &kraken],
+        QuoteType::BIDS,
+    );
+
+    assert_eq!(bids, vec![
+        level("Kraken", 11.0),
+        level("Binance", 10.0),
+        level("Bitstamp", 9.0),
+        level("Bitstamp", 8.0),
+        level("Binance", 7.0),
+    ]);
+}
@@ -0,0 +1,63 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// Exponential backoff with jitter between the reconnection attempts to an
+/// exchange.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial,
+            max: max.max(initial),
+            attempts: 0,
+        }
+    }
+
+    /// Starts over from the initial delay, once a connection has proven to
+    /// work again.
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+
+    /// Delay to wait before the next attempt. The delay doubles with every
+    /// attempt up to the maximum, and up to half of it is taken off at
+    /// random so that pipelines losing an exchange at the same time do not
+    /// all reconnect at once.
+    pub fn next_delay(&mut self) -> Duration {
+        let factor = 2u32.saturating_pow(self.attempts);
+        let delay = self.initial.saturating_mul(factor).min(self.max);
+        self.attempts = self.attempts.saturating_add(1);
+
+        delay.mul_f64(1.0 - random_fraction() / 2.0)
+    }
+}
+
+/// Random number in `[0, 1)`. `RandomState` is seeded randomly for every
+/// instance, which is all the randomness jitter needs.
+fn random_fraction() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[test]
+fn test_backoff_doubles_up_to_max_with_jitter() {
+    let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(1000));
+
+    let expected_caps = [100, 200, 400, 800, 1000, 1000];
+    for cap in expected_caps {
+        let delay = backoff.next_delay();
+        assert!(delay <= Duration::from_millis(cap));
+        assert!(delay >= Duration::from_millis(cap / 2));
+    }
+
+    backoff.reset();
+    assert!(backoff.next_delay() <= Duration::from_millis(100));
+}
@@ -1,49 +1,182 @@
-use serde::{Deserialize, Serialize};
-use tungstenite::Message;
+use std::time::Duration;
 
-use crate::{orderbook_aggregator::Summary, exchange::{Exchange, self, Socket}, order::{Order, summarise_order}, error::OrderbookError};
-use crate::order::Quote;
+use serde::Deserialize;
 
+use crate::{orderbook_aggregator::Summary, exchange::{Exchange, self, Socket}, error::OrderbookError};
+use crate::book::{OrderBook, Side};
+use crate::config::Config;
+
+pub static EXCHANGE_NAME: &str = "Binance";
 static WSS_BASE_ENDPOINT: &str = "wss://stream.binance.com:9443";
 
-#[derive(Clone, Serialize, Deserialize)]
+/// Number of levels per side requested in the REST snapshot of the book.
+const SNAPSHOT_LIMIT: usize = 1000;
+
+/// A `depthUpdate` event of the diff depth stream, carrying the absolute
+/// amount of every level that changed between update ids `U` and `u`.
+#[derive(Debug, Deserialize)]
+pub struct DepthUpdate {
+    #[serde(rename = "U")]
+    pub first_update_id: u64,
+    #[serde(rename = "u")]
+    pub final_update_id: u64,
+    #[serde(rename = "b")]
+    pub bids: Vec<(String, String)>,
+    #[serde(rename = "a")]
+    pub asks: Vec<(String, String)>,
+}
+
+/// Snapshot of the book returned by the `/api/v3/depth` REST endpoint.
+#[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct BinanceOrder {
-    pub last_update_id: usize,
-    pub asks: Vec<Quote>,
-    pub bids: Vec<Quote>,
+pub struct DepthSnapshot {
+    pub last_update_id: u64,
+    pub bids: Vec<(String, String)>,
+    pub asks: Vec<(String, String)>,
 }
 
-impl Order for BinanceOrder {
-    fn exchange_name(&self) -> String {
-        String::from("Binance")
+/// Keeps a local copy of the full Binance book following Binance's local
+/// order book procedure: diff depth events are read from the websocket, a
+/// REST snapshot is fetched, and events are applied from the one following
+/// the snapshot's `lastUpdateId`. The book is synced again from a new
+/// snapshot whenever an event is missed.
+pub struct Binance {
+    socket: Socket,
+    symbol: String,
+    rest_url: String,
+    depth: usize,
+    silence_timeout: Duration,
+    http: reqwest::Client,
+    book: OrderBook,
+    /// Id of the last update applied to `book`, or `None` while the book is
+    /// not synced with a snapshot.
+    last_update_id: Option<u64>,
+    /// `lastUpdateId` of the snapshot `book` was synced with.
+    snapshot_update_id: Option<u64>,
+    /// Number of times the book was found out of sync and synced again.
+    gaps: u64,
+}
+
+impl Binance {
+    pub fn new(symbol: String, config: &Config) -> Self {
+        Binance {
+            socket: None,
+            symbol,
+            rest_url: config.binance_rest_url.clone(),
+            depth: config.max_depth,
+            silence_timeout: config.silence_timeout(),
+            http: reqwest::Client::builder()
+                .timeout(config.silence_timeout())
+                .build()
+                .unwrap_or_default(),
+            book: OrderBook::default(),
+            last_update_id: None,
+            snapshot_update_id: None,
+            gaps: 0,
+        }
+    }
+
+    /// Fetches a snapshot of the book from the REST API, giving up after the
+    /// silence timeout.
+    async fn fetch_snapshot(&self) -> Result<DepthSnapshot, OrderbookError> {
+        let url = format!(
+            "{}/api/v3/depth?symbol={}&limit={}",
+            self.rest_url, self.symbol.to_uppercase(), SNAPSHOT_LIMIT);
+
+        let response = self.http.get(&url).send().await
+            .and_then(|response| response.error_for_status())
+            .map_err(|_| OrderbookError::ExchangeSnapshotError(url.clone()))?;
+
+        response.json().await
+            .map_err(|_| OrderbookError::ExchangeSnapshotError(url))
     }
 
-    fn asks(&self) -> &Vec<Quote> {
-        self.asks.as_ref()
+    /// Replaces the book with `snapshot`.
+    fn apply_snapshot(&mut self, snapshot: DepthSnapshot) -> Result<(), OrderbookError> {
+        self.book.clear();
+        self.last_update_id = Some(snapshot.last_update_id);
+        self.snapshot_update_id = Some(snapshot.last_update_id);
+
+        apply_levels(&mut self.book, Side::Bid, &snapshot.bids)?;
+        apply_levels(&mut self.book, Side::Ask, &snapshot.asks)
     }
 
-    fn bids(&self) -> &Vec<Quote> {
-        self.bids.as_ref()
+    /// Applies `update` to a synced book. Returns whether the book changed.
+    /// Updates already contained in the snapshot are skipped, and the book
+    /// is marked as out of sync when updates were missed before `update`.
+    /// Only updates missed after the book moved past its snapshot count as a
+    /// gap: a snapshot older than the first update left is expected from
+    /// time to time, and is simply fetched again.
+    fn apply_update(&mut self, update: DepthUpdate) -> Result<bool, OrderbookError> {
+        let last_update_id = match self.last_update_id {
+            Some(last_update_id) => last_update_id,
+            None => return Ok(false),
+        };
+
+        if update.final_update_id <= last_update_id {
+            return Ok(false);
+        }
+
+        if update.first_update_id > last_update_id + 1
+            && self.snapshot_update_id == Some(last_update_id) {
+            println!(
+                "{} {} snapshot {} is older than update {}, fetching it again",
+                EXCHANGE_NAME, self.symbol, last_update_id, update.first_update_id);
+            self.last_update_id = None;
+            return Ok(false);
+        }
+
+        if update.first_update_id > last_update_id + 1 {
+            println!(
+                "{} {} missed updates {} to {}, resyncing",
+                EXCHANGE_NAME, self.symbol, last_update_id + 1,
+                update.first_update_id - 1);
+            self.last_update_id = None;
+            self.gaps += 1;
+            return Ok(false);
+        }
+
+        apply_levels(&mut self.book, Side::Bid, &update.bids)?;
+        apply_levels(&mut self.book, Side::Ask, &update.asks)?;
+        self.last_update_id = Some(update.final_update_id);
+
+        Ok(true)
     }
 }
 
-pub struct Binance {
-    socket: Socket,
+fn apply_levels(
+    book: &mut OrderBook,
+    side: Side,
+    levels: &[(String, String)],
+) -> Result<(), OrderbookError> {
+    for (price, amount) in levels {
+        book.update(side, price, amount)?;
+    }
+
+    Ok(())
 }
 
+#[tonic::async_trait]
 impl Exchange for Binance {
-    fn new(symbol: String) -> Self {
+    async fn connect(&mut self) -> Result<(), OrderbookError> {
         let binance_endpoint = format!(
-            "{}/ws/{}@depth10@100ms",
-            WSS_BASE_ENDPOINT, symbol);
+            "{}/ws/{}@depth@100ms",
+            WSS_BASE_ENDPOINT, self.symbol);
 
-        Binance {
-            socket: exchange::connect(binance_endpoint.as_str()),
-        }
+        self.socket = None;
+        self.book.clear();
+        self.last_update_id = None;
+        self.snapshot_update_id = None;
+        self.socket = Some(exchange::connect(binance_endpoint.as_str()).await?);
+
+        Ok(())
+    }
+
+    async fn close(&mut self) {
+        exchange::close(&mut self.socket).await;
     }
 
-    fn stream(&mut self) -> Result<Option<Summary>, OrderbookError> {
+    async fn stream(&mut self) -> Result<Option<Summary>, OrderbookError> {
         let socket = match &mut self.socket {
             Some(socket) => socket,
             None => {
@@ -51,42 +184,96 @@ impl Exchange for Binance {
             }
         };
 
-        let mut last_sent_order: Option<BinanceOrder> = None;
+        let msg = exchange::read_text(socket, self.silence_timeout).await?;
 
-        let is_new_order = |curr_order: &BinanceOrder, last_sent_order: Option<BinanceOrder>| -> bool {
-            if last_sent_order.is_some() {
-                let last_order = last_sent_order.unwrap();
-                return curr_order.last_update_id != last_order.last_update_id
+        let update: DepthUpdate = match serde_json::from_str(&msg) {
+            Ok(update) => update,
+            Err(_) => {
+                return Err(OrderbookError::JsonParseError);
             }
-
-            true
         };
 
-        loop {
-            let msg = match socket.read_message() {
-                Ok(Message::Text(msg)) => msg,
-                Err(tungstenite::Error::ConnectionClosed) => {
-                    return Err(OrderbookError::NoConnectionError);
-                }
-                _ => {
-                    return Err(OrderbookError::SocketReadError);
-                }
-            };
-
-            let order: BinanceOrder = match serde_json::from_str(&msg) {
-                Ok(order) => order,
-                Err(_) => {
-                    return Err(OrderbookError::JsonParseError);
-                }
-            };
-
-            if is_new_order(&order, last_sent_order) {
-                last_sent_order = Some(order.clone());
-                return Ok(Some(summarise_order(order)));
-            }
-            else {
-                return Ok(None);
-            }
+        // The websocket buffers the events received while the snapshot is
+        // fetched, so they are applied once it arrives. A snapshot older
+        // than the first event left is of no use, and is fetched again on
+        // the next event.
+        if self.last_update_id.is_none() {
+            let snapshot = self.fetch_snapshot().await?;
+            self.apply_snapshot(snapshot)?;
+        }
+
+        if self.apply_update(update)? {
+            Ok(Some(self.book.summarise(EXCHANGE_NAME, self.depth)))
+        }
+        else {
+            Ok(None)
         }
     }
+
+    fn gaps(&self) -> u64 {
+        self.gaps
+    }
+}
+
+#[tokio::test]
+async fn test_binance_syncs_diff_updates_with_snapshot() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // A local stub standing in for the REST API.
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let rest_url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        let bodies = [
+            r#"{"lastUpdateId":90,"bids":[],"asks":[]}"#,
+            r#"{"lastUpdateId":100,"bids":[["10.0","1.0"]],"asks":[["11.0","1.0"]]}"#,
+        ];
+        for body in bodies {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).await.unwrap();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(), body);
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    });
+
+    let config = Config {
+        binance_rest_url: rest_url,
+        ..Config::default()
+    };
+    let mut binance = Binance::new(String::from("btcusdt"), &config);
+    let update = |first_update_id, final_update_id, bid: &str| DepthUpdate {
+        first_update_id,
+        final_update_id,
+        bids: vec![(String::from(bid), String::from("2.0"))],
+        asks: Vec::new(),
+    };
+
+    // A snapshot lagging behind the first update is fetched again, without
+    // counting a gap.
+    let snapshot = binance.fetch_snapshot().await.unwrap();
+    binance.apply_snapshot(snapshot).unwrap();
+    assert!(!binance.apply_update(update(95, 100, "9.0")).unwrap());
+    assert_eq!(binance.last_update_id, None);
+    assert_eq!(binance.gaps, 0);
+
+    let snapshot = binance.fetch_snapshot().await.unwrap();
+    binance.apply_snapshot(snapshot).unwrap();
+
+    // Already part of the snapshot.
+    assert!(!binance.apply_update(update(90, 100, "9.0")).unwrap());
+    // Straddles the snapshot, then follows on.
+    assert!(binance.apply_update(update(95, 105, "10.5")).unwrap());
+    assert!(binance.apply_update(update(106, 110, "10.0")).unwrap());
+
+    let summary = binance.book.summarise(EXCHANGE_NAME, 10);
+    assert_eq!(summary.bids.len(), 2);
+    assert_eq!(summary.bids[0].price, 10.5);
+    assert_eq!(summary.bids[1].amount, 2.0);
+
+    // Updates 111 to 114 were missed.
+    assert!(!binance.apply_update(update(115, 120, "9.5")).unwrap());
+    assert_eq!(binance.last_update_id, None);
+    assert_eq!(binance.gaps, 1);
 }
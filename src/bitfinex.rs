@@ -0,0 +1,335 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::json;
+use serde_json::value::RawValue;
+
+use crate::book::{OrderBook, Side};
+use crate::config::Config;
+use crate::error::OrderbookError;
+use crate::exchange::{Exchange, self, Socket};
+use crate::orderbook_aggregator::Summary;
+
+pub static EXCHANGE_NAME: &str = "Bitfinex";
+static WSS_BASE_ENDPOINT: &str = "wss://api-pub.bitfinex.com/ws/2";
+
+/// Flag of the `conf` event making Bitfinex send a checksum of the book
+/// after every update.
+const OB_CHECKSUM: u32 = 131072;
+
+/// Book lengths Bitfinex accepts in a subscription.
+static BOOK_LENGTHS: [usize; 3] = [25, 100, 250];
+
+/// Currencies Bitfinex names differently from other exchanges.
+static CURRENCY_CODES: [(&str, &str); 2] = [("USDT", "UST"), ("USDC", "UDC")];
+
+/// Number of levels per side covered by Bitfinex's book checksum.
+const CHECKSUM_DEPTH: usize = 25;
+
+/// An event sent by Bitfinex in reply to a request. Channel data is sent as
+/// arrays instead.
+#[derive(Deserialize)]
+pub struct BitfinexEvent {
+    pub event: String,
+    pub channel: Option<String>,
+    #[serde(rename = "chanId")]
+    pub chan_id: Option<u64>,
+    pub msg: Option<String>,
+}
+
+/// A level as `[price, count, amount]`. The amount of an ask is negative and
+/// a count of zero removes the level. The numbers are kept as Bitfinex wrote
+/// them, which is what its checksum is computed over.
+type BitfinexLevel<'a> = (&'a RawValue, u64, &'a RawValue);
+
+/// Keeps a local copy of the Bitfinex book from the precision P0 `book`
+/// channel, from the snapshot array and the level updates that follow it.
+/// The book is verified against the checksum Bitfinex sends after every
+/// update, and subscribed to again when it does not match.
+pub struct Bitfinex {
+    socket: Socket,
+    symbol: String,
+    depth: usize,
+    silence_timeout: Duration,
+    length: usize,
+    book: OrderBook,
+    /// Id of the channel of the book subscription, once Bitfinex confirmed
+    /// it.
+    chan_id: Option<u64>,
+    /// Whether `book` holds a snapshot that the updates apply to.
+    is_synced: bool,
+    /// Number of times the book was found out of sync and synced again.
+    gaps: u64,
+}
+
+impl Bitfinex {
+    pub fn new(symbol: String, config: &Config) -> Self {
+        let length = BOOK_LENGTHS.iter()
+            .copied()
+            .find(|length| *length >= config.max_depth)
+            .unwrap_or(BOOK_LENGTHS[BOOK_LENGTHS.len() - 1]);
+
+        Bitfinex {
+            socket: None,
+            symbol,
+            depth: config.max_depth,
+            silence_timeout: config.silence_timeout(),
+            length,
+            book: OrderBook::default(),
+            chan_id: None,
+            is_synced: false,
+            gaps: 0,
+        }
+    }
+
+    /// Bitfinex's name for the trading pair, such as `tBTCUST`, or such as
+    /// `tMATIC:USD` when a currency code is longer than three letters.
+    fn pair(&self) -> Result<String, OrderbookError> {
+        let code = |currency: String| -> String {
+            CURRENCY_CODES.iter()
+                .find(|(name, _)| *name == currency)
+                .map(|(_, code)| String::from(*code))
+                .unwrap_or(currency)
+        };
+
+        let (base, quote) = exchange::split_symbol(&self.symbol)
+            .map(|(base, quote)| (code(base), code(quote)))
+            .ok_or_else(|| OrderbookError::UnknownSymbolError(self.symbol.clone()))?;
+
+        if base.len() > 3 || quote.len() > 3 {
+            Ok(format!("t{}:{}", base, quote))
+        }
+        else {
+            Ok(format!("t{}{}", base, quote))
+        }
+    }
+
+    fn subscribe_message(&self) -> Result<String, OrderbookError> {
+        Ok(json!({
+            "event": "subscribe",
+            "channel": "book",
+            "symbol": self.pair()?,
+            "prec": "P0",
+            "freq": "F0",
+            "len": self.length.to_string(),
+        }).to_string())
+    }
+
+    fn unsubscribe_message(&self) -> Option<String> {
+        self.chan_id.map(|chan_id| json!({
+            "event": "unsubscribe",
+            "chanId": chan_id,
+        }).to_string())
+    }
+
+    /// Leaves the book channel, when Bitfinex has confirmed it, and
+    /// subscribes again. Messages are ignored until the new channel id is
+    /// confirmed and its snapshot arrives.
+    async fn resubscribe(&mut self) -> Result<(), OrderbookError> {
+        self.book.clear();
+        self.is_synced = false;
+
+        let unsubscribe_message = self.unsubscribe_message();
+        self.chan_id = None;
+        let subscribe_message = self.subscribe_message()?;
+        exchange::resubscribe(
+            &mut self.socket,
+            unsubscribe_message,
+            subscribe_message,
+        ).await
+    }
+
+    fn apply_level(&mut self, (price, count, amount): BitfinexLevel) -> Result<(), OrderbookError> {
+        let (side, amount) = match amount.get().strip_prefix('-') {
+            Some(amount) => (Side::Ask, amount),
+            None => (Side::Bid, amount.get()),
+        };
+
+        match count {
+            0 => self.book.update(side, price.get(), "0"),
+            _ => self.book.update(side, price.get(), amount),
+        }
+    }
+
+    /// Applies the Bitfinex message `msg` to the book. Returns whether the
+    /// book changed, and fails with a checksum error when the book no longer
+    /// matches Bitfinex's.
+    fn apply_message(&mut self, msg: &str) -> Result<bool, OrderbookError> {
+        let parse_error = |_| OrderbookError::JsonParseError;
+
+        if msg.trim_start().starts_with('{') {
+            let event: BitfinexEvent = serde_json::from_str(msg).map_err(parse_error)?;
+            match event.event.as_str() {
+                "subscribed" if event.channel.as_deref() == Some("book") => {
+                    self.chan_id = event.chan_id;
+                }
+                "error" => {
+                    println!("{} Error: {}", EXCHANGE_NAME, event.msg.unwrap_or_default());
+                    return Err(OrderbookError::SubscriptionError);
+                }
+                _ => (),
+            }
+            return Ok(false);
+        }
+
+        let fields: Vec<&RawValue> = serde_json::from_str(msg).map_err(parse_error)?;
+        let (chan_id, payload) = match fields.as_slice() {
+            [chan_id, payload, ..] => (chan_id.get(), payload.get().trim_start()),
+            _ => return Err(OrderbookError::JsonParseError),
+        };
+        if chan_id.parse::<u64>().ok() != self.chan_id {
+            return Ok(false);
+        }
+
+        match payload {
+            "\"hb\"" => Ok(false),
+            "\"cs\"" => {
+                let expected: i32 = fields.get(2)
+                    .ok_or(OrderbookError::JsonParseError)
+                    .and_then(|checksum| serde_json::from_str(checksum.get()).map_err(parse_error))?;
+
+                if self.is_synced && checksum(&self.book) != expected as u32 {
+                    self.is_synced = false;
+                    return Err(OrderbookError::ChecksumError);
+                }
+                Ok(false)
+            }
+            _ if payload.starts_with("[[") || payload == "[]" => {
+                let levels: Vec<BitfinexLevel> = serde_json::from_str(payload).map_err(parse_error)?;
+
+                self.book.clear();
+                for level in levels {
+                    self.apply_level(level)?;
+                }
+                self.is_synced = true;
+                Ok(true)
+            }
+            _ if self.is_synced => {
+                let level: BitfinexLevel = serde_json::from_str(payload).map_err(parse_error)?;
+                self.apply_level(level)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+/// Bitfinex's checksum of `book`: the CRC32 of the price and amount of the
+/// top 25 bids and asks as sent by Bitfinex, alternating a bid and an ask,
+/// separated by colons and with the amounts of asks negative.
+fn checksum(book: &OrderBook) -> u32 {
+    let fields: Vec<String> = book.interleave(CHECKSUM_DEPTH).into_iter()
+        .flat_map(|(side, level)| {
+            let amount = match side {
+                Side::Bid => level.raw_amount.clone(),
+                Side::Ask => format!("-{}", level.raw_amount),
+            };
+            [level.raw_price.clone(), amount]
+        })
+        .collect();
+
+    crc32fast::hash(fields.join(":").as_bytes())
+}
+
+#[tonic::async_trait]
+impl Exchange for Bitfinex {
+    async fn connect(&mut self) -> Result<(), OrderbookError> {
+        self.socket = None;
+        self.book.clear();
+        self.chan_id = None;
+        self.is_synced = false;
+
+        let conf_message = json!({
+            "event": "conf",
+            "flags": OB_CHECKSUM,
+        }).to_string();
+
+        let subscribe_message = self.subscribe_message()?;
+
+        let mut socket = exchange::connect(WSS_BASE_ENDPOINT).await?;
+        exchange::send_text(&mut socket, conf_message).await?;
+        exchange::send_text(&mut socket, subscribe_message).await?;
+        self.socket = Some(socket);
+
+        Ok(())
+    }
+
+    async fn close(&mut self) {
+        if let Some(unsubscribe_message) = self.unsubscribe_message() {
+            let _ = exchange::send(&mut self.socket, unsubscribe_message).await;
+        }
+
+        exchange::close(&mut self.socket).await;
+    }
+
+    async fn stream(&mut self) -> Result<Option<Summary>, OrderbookError> {
+        loop {
+            let socket = match &mut self.socket {
+                Some(socket) => socket,
+                None => {
+                    return Err(OrderbookError::NoConnectionError);
+                }
+            };
+
+            let msg = exchange::read_text(socket, self.silence_timeout).await?;
+
+            match self.apply_message(&msg) {
+                Ok(true) => {
+                    return Ok(Some(self.book.summarise(EXCHANGE_NAME, self.depth)));
+                }
+                Ok(false) => (),
+                Err(OrderbookError::ChecksumError) => {
+                    println!(
+                        "{} {} checksum mismatch, resubscribing",
+                        EXCHANGE_NAME, self.symbol);
+                    self.gaps += 1;
+                    self.resubscribe().await?;
+                    return Ok(None);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn gaps(&self) -> u64 {
+        self.gaps
+    }
+}
+
+#[test]
+fn test_bitfinex_applies_arrays_and_verifies_checksum() {
+    let pair = |symbol: &str| Bitfinex::new(String::from(symbol), &Config::default()).pair();
+    assert_eq!(pair("btcusd").unwrap(), "tBTCUSD");
+    assert_eq!(pair("btcusdt").unwrap(), "tBTCUST");
+    assert_eq!(pair("maticusd").unwrap(), "tMATIC:USD");
+    assert_eq!(pair("ethusdc").unwrap(), "tETHUDC");
+    assert!(pair("btcxyz").is_err());
+
+    let mut bitfinex = Bitfinex::new(String::from("btcusd"), &Config::default());
+    let subscribed = r#"{"event":"subscribed","channel":"book","chanId":17082,"symbol":"tBTCUSD","prec":"P0","freq":"F0","len":"100"}"#;
+    assert!(!bitfinex.apply_message(subscribed).unwrap());
+
+    // Messages of other channels are ignored.
+    assert!(!bitfinex.apply_message(r#"[9,[[1,1,1]]]"#).unwrap());
+
+    let snapshot = r#"[17082,[[7254.7,3,3.3],[7254.5,1,0.025],[7255.1,2,-1.5e-7]]]"#;
+    assert!(bitfinex.apply_message(snapshot).unwrap());
+    assert!(!bitfinex.apply_message(r#"[17082,"hb"]"#).unwrap());
+
+    // The best bid goes, and an ask changes.
+    assert!(bitfinex.apply_message(r#"[17082,[7254.7,0,1]]"#).unwrap());
+    assert!(bitfinex.apply_message(r#"[17082,[7255.1,4,-2.5]]"#).unwrap());
+
+    let summary = bitfinex.book.summarise(EXCHANGE_NAME, 10);
+    assert_eq!(summary.bids[0].price, 7254.5);
+    assert_eq!(summary.asks[0].amount, 2.5);
+
+    let expected = crc32fast::hash(b"7254.5:0.025:7255.1:-2.5") as i32;
+    let checksum = format!("[17082,\"cs\",{}]", expected);
+    assert!(!bitfinex.apply_message(&checksum).unwrap());
+
+    let checksum = format!("[17082,\"cs\",{}]", expected.wrapping_add(1));
+    assert!(matches!(
+        bitfinex.apply_message(&checksum),
+        Err(OrderbookError::ChecksumError)));
+}
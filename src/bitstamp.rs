@@ -1,130 +1,330 @@
+use std::time::Duration;
+
 use serde::{Deserialize, Serialize};
+use serde::de::IgnoredAny;
 use serde_json::json;
-use tungstenite::Message;
 
+use crate::book::{OrderBook, Side};
+use crate::config::Config;
 use crate::error::OrderbookError;
-use crate::exchange::{Exchange, self, Socket};
-use crate::order::{Quote, Order, summarise_order};
+use crate::exchange::{Exchange, self, Socket, WebSocket};
+use crate::order::Quote;
 use crate::orderbook_aggregator::Summary;
 
+pub static EXCHANGE_NAME: &str = "Bitstamp";
 static WSS_BASE_ENDPOINT: &str = "wss://ws.bitstamp.net";
 
+/// Levels of the book that changed at `microtimestamp`, as sent on the
+/// `diff_order_book` channel. The REST snapshot of the book has the same
+/// shape.
 #[derive(Clone, Deserialize, Serialize)]
 pub struct BitstampOrder {
-    pub timestamp: String,
     pub microtimestamp: String,
     pub bids: Vec<Quote>,
     pub asks: Vec<Quote>,
 }
 
-impl Order for BitstampOrder {
-    fn exchange_name(&self) -> String {
-        String::from("Bitstamp")
-    }
-
-    fn asks(&self) -> &Vec<Quote> {
-        self.asks.as_ref()
-    }
-
-    fn bids(&self) -> &Vec<Quote> {
-        self.bids.as_ref()
-    }
-}
-
-
 #[derive(Deserialize)]
 #[serde(untagged)]
 pub enum Data {
     Order(BitstampOrder),
-    None {},
+    Error {
+        message: String,
+    },
+    /// Events such as `bts:request_reconnect` carry no data, sent as an
+    /// empty object or an empty string.
+    None(IgnoredAny),
 }
 
 #[derive(Deserialize)]
 pub struct BitstampMsg {
     pub event: String,
-    pub channel: String,
     pub data: Data,
 }
 
+/// What a Bitstamp message means for the connection.
+pub enum BitstampEvent {
+    /// Levels of the book changed.
+    Diff(BitstampOrder),
+    /// Bitstamp confirmed the subscription to the book.
+    Subscribed,
+    /// Bitstamp is about to close the connection, for maintenance, and asks
+    /// to be connected to again.
+    Reconnect,
+    /// A message that needs no action.
+    Other,
+}
+
+impl BitstampEvent {
+    /// Parses the Bitstamp message `msg`. A `bts:error` event is returned as
+    /// an error.
+    fn parse(msg: &str) -> Result<Self, OrderbookError> {
+        let bitstamp_msg: BitstampMsg = match serde_json::from_str(msg) {
+            Ok(bitstamp_msg) => bitstamp_msg,
+            Err(_) => {
+                return Err(OrderbookError::JsonParseError);
+            }
+        };
+
+        match (bitstamp_msg.event.as_str(), bitstamp_msg.data) {
+            ("data", Data::Order(diff)) => Ok(BitstampEvent::Diff(diff)),
+            ("bts:subscription_succeeded", _) => Ok(BitstampEvent::Subscribed),
+            ("bts:request_reconnect", _) => Ok(BitstampEvent::Reconnect),
+            ("bts:error", Data::Error { message }) => Err(OrderbookError::ExchangeError(message)),
+            ("bts:error", _) => Err(OrderbookError::ExchangeError(String::new())),
+            _ => Ok(BitstampEvent::Other),
+        }
+    }
+}
+
+/// Keeps a local copy of the full Bitstamp book. The book is seeded from a
+/// REST snapshot and the diffs of the `diff_order_book` channel are applied
+/// in `microtimestamp` order, skipping the ones the snapshot already holds.
+/// The connection is only reported as open once Bitstamp has confirmed the
+/// subscription, and is replaced by a new one as soon as Bitstamp announces
+/// it is going to close it.
 pub struct Bitstamp {
     socket: Socket,
     symbol: String,
+    rest_url: String,
+    depth: usize,
+    silence_timeout: Duration,
+    http: reqwest::Client,
+    book: OrderBook,
+    /// Microtimestamp of the last diff applied to `book`, or `None` while
+    /// the book is not seeded from a snapshot.
+    last_microtimestamp: Option<u64>,
+    /// Microtimestamp of the snapshot `book` was seeded from.
+    snapshot_microtimestamp: Option<u64>,
+    /// Number of times the book was found out of sync and synced again.
+    gaps: u64,
 }
 
-impl Exchange for Bitstamp {
-    fn new(symbol: String) -> Self {
-        let bitstamp_endpoint = format!("{}", WSS_BASE_ENDPOINT);
-
+impl Bitstamp {
+    pub fn new(symbol: String, config: &Config) -> Self {
         Bitstamp {
-            socket: exchange::connect(bitstamp_endpoint.as_str()),
+            socket: None,
             symbol,
+            rest_url: config.bitstamp_rest_url.clone(),
+            depth: config.max_depth,
+            silence_timeout: config.silence_timeout(),
+            http: reqwest::Client::builder()
+                .timeout(config.silence_timeout())
+                .build()
+                .unwrap_or_default(),
+            book: OrderBook::default(),
+            last_microtimestamp: None,
+            snapshot_microtimestamp: None,
+            gaps: 0,
         }
     }
 
-    fn stream(&mut self) -> Result<Option<Summary>, OrderbookError> {
-        let socket = match &mut self.socket {
-            Some(socket) => socket,
-            None => {
-                panic!("No existing connection to Binance exchange");
-            }
-        };
-
-        let subscribe_message = json!(
+    fn channel_message(&self, event: &str) -> String {
+        json!(
         {
-            "event": "bts:subscribe",
+            "event": event,
             "data": {
-                "channel": format!("order_book_{}", self.symbol)
+                "channel": format!("diff_order_book_{}", self.symbol)
             }
-        });
+        }).to_string()
+    }
 
-        match socket.write_message(Message::Text(subscribe_message.to_string())) {
-            Err(_) => {
-                return Err(OrderbookError::SubscriptionError);
-            },
-            _ => ()
-        };
+    /// Subscribes to the book on `socket` and waits for Bitstamp to confirm
+    /// the subscription. Messages received before the confirmation are
+    /// skipped.
+    async fn subscribe(&self, socket: &mut WebSocket) -> Result<(), OrderbookError> {
+        exchange::send_text(socket, self.channel_message("bts:subscribe")).await?;
+
+        loop {
+            let msg = exchange::read_text(socket, self.silence_timeout).await?;
+            if let BitstampEvent::Subscribed = BitstampEvent::parse(&msg)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Replaces the connection with a new one, subscribed before the old one
+    /// is closed. The book is synced again from a new snapshot, since diffs
+    /// may be missed between the two connections.
+    async fn reconnect(&mut self) -> Result<(), OrderbookError> {
+        let mut socket = exchange::connect(WSS_BASE_ENDPOINT).await?;
+        self.subscribe(&mut socket).await?;
+
+        let mut previous = self.socket.replace(socket);
+        exchange::close(&mut previous).await;
+        self.last_microtimestamp = None;
+
+        Ok(())
+    }
+
+    /// Fetches a snapshot of the book from the REST API, giving up after the
+    /// silence timeout.
+    async fn fetch_snapshot(&self) -> Result<BitstampOrder, OrderbookError> {
+        let url = format!("{}/api/v2/order_book/{}/", self.rest_url, self.symbol);
 
-        let mut last_sent_order: Option<BitstampOrder> = None;
+        let response = self.http.get(&url).send().await
+            .and_then(|response| response.error_for_status())
+            .map_err(|_| OrderbookError::ExchangeSnapshotError(url.clone()))?;
 
-        let is_new_order = |order: &BitstampOrder| -> bool {
-            if last_sent_order.is_some() {
-                let last_order = last_sent_order.unwrap();
-                return order.timestamp != last_order.timestamp &&
-                       order.microtimestamp != last_order.microtimestamp
+        response.json().await
+            .map_err(|_| OrderbookError::ExchangeSnapshotError(url))
+    }
+
+    /// Replaces the book with `snapshot`.
+    fn apply_snapshot(&mut self, snapshot: BitstampOrder) -> Result<(), OrderbookError> {
+        self.book.clear();
+        self.last_microtimestamp = None;
+        self.apply_diff(snapshot)?;
+        self.snapshot_microtimestamp = self.last_microtimestamp;
+
+        Ok(())
+    }
+
+    /// Applies `diff` to the book unless the book is already as recent.
+    /// Returns whether the book changed. Diffs older than the snapshot are
+    /// expected while it is fetched, but a diff older than one applied
+    /// after the snapshot arrived out of order, and the book is out of sync.
+    fn apply_diff(&mut self, diff: BitstampOrder) -> Result<bool, OrderbookError> {
+        let microtimestamp = diff.microtimestamp.parse::<u64>()
+            .map_err(|_| OrderbookError::JsonParseError)?;
+
+        if let Some(last_microtimestamp) = self.last_microtimestamp {
+            if microtimestamp < last_microtimestamp
+                && self.snapshot_microtimestamp != Some(last_microtimestamp) {
+                return Err(OrderbookError::SequenceGapError);
+            }
+            if microtimestamp <= last_microtimestamp {
+                return Ok(false);
             }
+        }
 
-            true
-        };
+        for bid in &diff.bids {
+            self.book.update_quote(Side::Bid, bid);
+        }
+        for ask in &diff.asks {
+            self.book.update_quote(Side::Ask, ask);
+        }
+        self.last_microtimestamp = Some(microtimestamp);
+
+        Ok(true)
+    }
+}
+
+#[tonic::async_trait]
+impl Exchange for Bitstamp {
+    async fn connect(&mut self) -> Result<(), OrderbookError> {
+        self.socket = None;
+        self.book.clear();
+        self.last_microtimestamp = None;
+
+        let mut socket = exchange::connect(WSS_BASE_ENDPOINT).await?;
+        self.subscribe(&mut socket).await?;
+        self.socket = Some(socket);
+
+        Ok(())
+    }
 
+    async fn close(&mut self) {
+        let unsubscribe_message = self.channel_message("bts:unsubscribe");
+        let _ = exchange::send(&mut self.socket, unsubscribe_message).await;
+
+        exchange::close(&mut self.socket).await;
+    }
+
+    async fn stream(&mut self) -> Result<Option<Summary>, OrderbookError> {
         loop {
-            let msg = match socket.read_message() {
-                Ok(Message::Text(msg)) => msg,
-                Err(tungstenite::Error::ConnectionClosed) => {
+            let socket = match &mut self.socket {
+                Some(socket) => socket,
+                None => {
                     return Err(OrderbookError::NoConnectionError);
                 }
-                _ => {
-                    return Err(OrderbookError::SocketReadError);
-                }
             };
 
-            let bitstamp_msg: BitstampMsg = match serde_json::from_str(&msg) {
-                Ok(bitstamp_msg) => bitstamp_msg,
-                Err(_) => {
-                    return Err(OrderbookError::JsonParseError);
-                }
-            };
+            let msg = exchange::read_text(socket, self.silence_timeout).await?;
 
-            if bitstamp_msg.event == "data" {
-                if let Data::Order(order) = bitstamp_msg.data {
-                    if is_new_order(&order) {
-                        last_sent_order = Some(order.clone());
-                        return Ok(Some(summarise_order(order)));
+            match BitstampEvent::parse(&msg)? {
+                BitstampEvent::Diff(diff) => {
+                    // Diffs received while the snapshot is fetched wait on
+                    // the websocket, and the ones older than the snapshot
+                    // are skipped once it arrives.
+                    if self.last_microtimestamp.is_none() {
+                        let snapshot = self.fetch_snapshot().await?;
+                        self.apply_snapshot(snapshot)?;
                     }
-                    else {
-                        return Ok(None);
+
+                    match self.apply_diff(diff) {
+                        Ok(true) => {
+                            return Ok(Some(self.book.summarise(EXCHANGE_NAME, self.depth)));
+                        }
+                        Ok(false) => return Ok(None),
+                        Err(OrderbookError::SequenceGapError) => {
+                            println!(
+                                "{} {} diff out of order, resyncing",
+                                EXCHANGE_NAME, self.symbol);
+                            self.last_microtimestamp = None;
+                            self.gaps += 1;
+                            return Ok(None);
+                        }
+                        Err(e) => return Err(e),
                     }
                 }
+                BitstampEvent::Reconnect => {
+                    println!("{} {} asked to reconnect, reconnecting", EXCHANGE_NAME, self.symbol);
+                    self.reconnect().await?;
+                    return Ok(None);
+                }
+                BitstampEvent::Subscribed | BitstampEvent::Other => (),
             }
         }
     }
+
+    fn gaps(&self) -> u64 {
+        self.gaps
+    }
+}
+
+#[test]
+fn test_bitstamp_applies_diffs_after_snapshot() {
+    let order = |microtimestamp: &str, bids: serde_json::Value| -> BitstampOrder {
+        let order = json!({
+            "microtimestamp": microtimestamp,
+            "bids": bids,
+            "asks": [["11.0", "1.0"]],
+        });
+        serde_json::from_str(&order.to_string()).unwrap()
+    };
+
+    let mut bitstamp = Bitstamp::new(String::from("btcusd"), &Config::default());
+    bitstamp.apply_snapshot(order("1000", json!([["10.0", "1.0"], ["9.0", "1.0"]]))).unwrap();
+
+    // Older than the snapshot.
+    assert!(!bitstamp.apply_diff(order("999", json!([["10.5", "1.0"]]))).unwrap());
+    assert!(bitstamp.apply_diff(order("1001", json!([["10.0", "0.0"]]))).unwrap());
+    assert!(!bitstamp.apply_diff(order("1001", json!([["10.0", "2.0"]]))).unwrap());
+    assert!(matches!(
+        bitstamp.apply_diff(order("1000", json!([["8.0", "1.0"]]))),
+        Err(OrderbookError::SequenceGapError)));
+
+    let summary = bitstamp.book.summarise(EXCHANGE_NAME, 10);
+    assert_eq!(summary.bids.len(), 1);
+    assert_eq!(summary.bids[0].price, 9.0);
+    assert_eq!(summary.spread, -2.0);
+}
+
+#[test]
+fn test_bitstamp_parses_control_events() {
+    let event = |msg: &str| BitstampEvent::parse(msg);
+
+    assert!(matches!(
+        event(r#"{"event":"bts:subscription_succeeded","channel":"diff_order_book_btcusd","data":{}}"#),
+        Ok(BitstampEvent::Subscribed)));
+    assert!(matches!(
+        event(r#"{"event":"bts:request_reconnect","channel":"","data":""}"#),
+        Ok(BitstampEvent::Reconnect)));
+    assert!(matches!(
+        event(r#"{"event":"bts:error","channel":"","data":{"code":null,"message":"Bad subscription string."}}"#),
+        Err(OrderbookError::ExchangeError(message)) if message == "Bad subscription string."));
+    assert!(matches!(
+        event(r#"{"event":"data","channel":"diff_order_book_btcusd","data":{"microtimestamp":"1","bids":[],"asks":[]}}"#),
+        Ok(BitstampEvent::Diff(_))));
 }
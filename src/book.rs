@@ -0,0 +1,202 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use crate::aggregator::Aggregator;
+use crate::error::OrderbookError;
+use crate::order::Quote;
+use crate::orderbook_aggregator::{Level, Summary};
+
+/// Price of a book level, ordered by value so that it can key a side of the
+/// book.
+#[derive(Debug, Clone, Copy)]
+pub struct Price(pub f64);
+
+impl PartialEq for Price {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Price {}
+
+impl PartialOrd for Price {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Price {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Side {
+    Bid,
+    Ask,
+}
+
+/// A level of a local orderbook. The price and amount are also kept exactly
+/// as the exchange sent them, since some exchanges checksum the book over
+/// that text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BookLevel {
+    pub price: f64,
+    pub amount: f64,
+    pub raw_price: String,
+    pub raw_amount: String,
+}
+
+/// Orderbook of one symbol on one exchange, maintained locally from a
+/// snapshot and the incremental updates that follow it.
+#[derive(Debug, Default)]
+pub struct OrderBook {
+    bids: BTreeMap<Price, BookLevel>,
+    asks: BTreeMap<Price, BookLevel>,
+}
+
+impl OrderBook {
+    pub fn clear(&mut self) {
+        self.bids.clear();
+        self.asks.clear();
+    }
+
+    /// Sets the amount at `price` on `side` of the book. A zero amount
+    /// removes the level.
+    pub fn update(
+        &mut self,
+        side: Side,
+        price: &str,
+        amount: &str,
+    ) -> Result<(), OrderbookError> {
+        let parse = |text: &str| {
+            text.parse::<f64>().map_err(|_| OrderbookError::JsonParseError)
+        };
+
+        let level = BookLevel {
+            price: parse(price)?,
+            amount: parse(amount)?,
+            raw_price: String::from(price),
+            raw_amount: String::from(amount),
+        };
+
+        self.set(side, level);
+
+        Ok(())
+    }
+
+    /// Sets the amount of `quote` on `side` of the book, for exchanges whose
+    /// quotes are parsed as they are read. A zero amount removes the level.
+    pub fn update_quote(&mut self, side: Side, quote: &Quote) {
+        self.set(side, BookLevel {
+            price: quote.price,
+            amount: quote.amount,
+            raw_price: quote.price.to_string(),
+            raw_amount: quote.amount.to_string(),
+        });
+    }
+
+    fn set(&mut self, side: Side, level: BookLevel) {
+        let levels = match side {
+            Side::Bid => &mut self.bids,
+            Side::Ask => &mut self.asks,
+        };
+
+        if level.amount == 0.0 {
+            levels.remove(&Price(level.price));
+        }
+        else {
+            levels.insert(Price(level.price), level);
+        }
+    }
+
+    /// Drops the levels beyond the best `depth` of each side, for exchanges
+    /// that only maintain a book of a limited depth.
+    pub fn truncate(&mut self, depth: usize) {
+        while self.bids.len() > depth {
+            self.bids.pop_first();
+        }
+        while self.asks.len() > depth {
+            self.asks.pop_last();
+        }
+    }
+
+    /// Bids from the highest price down.
+    pub fn bids(&self) -> impl Iterator<Item = &BookLevel> {
+        self.bids.values().rev()
+    }
+
+    /// Asks from the lowest price up.
+    pub fn asks(&self) -> impl Iterator<Item = &BookLevel> {
+        self.asks.values()
+    }
+
+    /// The top `depth` levels of each side, alternating a bid and an ask from
+    /// the best ones down, as some exchanges checksum the book.
+    pub fn interleave(&self, depth: usize) -> Vec<(Side, &BookLevel)> {
+        let mut bids = self.bids().take(depth);
+        let mut asks = self.asks().take(depth);
+
+        let mut levels = Vec::new();
+        loop {
+            let (bid, ask) = (bids.next(), asks.next());
+            if bid.is_none() && ask.is_none() {
+                break;
+            }
+
+            levels.extend(bid.map(|bid| (Side::Bid, bid)));
+            levels.extend(ask.map(|ask| (Side::Ask, ask)));
+        }
+
+        levels
+    }
+
+    /// Summarises the top `depth` levels of each side of the book as quoted
+    /// by `exchange`.
+    pub fn summarise(&self, exchange: &str, depth: usize) -> Summary {
+        let to_level = |level: &BookLevel| Level {
+            exchange: String::from(exchange),
+            price: level.price,
+            amount: level.amount,
+        };
+
+        let bids: Vec<Level> = self.bids().take(depth).map(to_level).collect();
+        let asks: Vec<Level> = self.asks().take(depth).map(to_level).collect();
+
+        Summary {
+            spread: Aggregator::spread(&bids, &asks),
+            bids,
+            asks,
+            symbol: String::new(),
+        }
+    }
+}
+
+#[test]
+fn test_book_applies_updates_and_keeps_best_levels_first() {
+    let mut book = OrderBook::default();
+    book.update(Side::Bid, "10.0", "1.0").unwrap();
+    book.update(Side::Bid, "11.0", "2.0").unwrap();
+    book.update(Side::Ask, "12.5", "3.0").unwrap();
+    book.update(Side::Ask, "12.0", "4.0").unwrap();
+
+    book.update(Side::Bid, "10.0", "1.5").unwrap();
+    book.update(Side::Ask, "12.0", "0.00000000").unwrap();
+    assert!(book.update(Side::Ask, "twelve", "1.0").is_err());
+
+    let summary = book.summarise("Binance", 1);
+    assert_eq!(summary.bids, vec![Level {
+        exchange: String::from("Binance"),
+        price: 11.0,
+        amount: 2.0,
+    }]);
+    assert_eq!(summary.asks[0].price, 12.5);
+    assert_eq!(summary.spread, -1.5);
+
+    let bids: Vec<&str> = book.bids().map(|level| level.raw_amount.as_str()).collect();
+    assert_eq!(bids, vec!["2.0", "1.5"]);
+
+    book.clear();
+    assert_eq!(book.asks().count(), 0);
+}
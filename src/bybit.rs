@@ -0,0 +1,230 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::json;
+use tokio::time::{self, Instant, Interval};
+
+use crate::book::{OrderBook, Side};
+use crate::config::Config;
+use crate::error::OrderbookError;
+use crate::exchange::{Exchange, self, Socket};
+use crate::orderbook_aggregator::Summary;
+
+pub static EXCHANGE_NAME: &str = "Bybit";
+static WSS_BASE_ENDPOINT: &str = "wss://stream.bybit.com/v5/public/spot";
+
+/// Depth of the `orderbook.50` topic.
+const BOOK_DEPTH: usize = 50;
+
+/// How often Bybit expects a ping to keep the connection open.
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Any message sent by Bybit. Pushes of the book carry a `topic`, while
+/// replies to requests carry the `op` they answer.
+#[derive(Deserialize)]
+pub struct BybitMsg {
+    pub topic: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub data: Option<BybitBook>,
+    pub success: Option<bool>,
+    pub ret_msg: Option<String>,
+}
+
+/// Snapshot or delta of the book, where a zero size removes the level.
+#[derive(Deserialize)]
+pub struct BybitBook {
+    #[serde(rename = "b")]
+    pub bids: Vec<(String, String)>,
+    #[serde(rename = "a")]
+    pub asks: Vec<(String, String)>,
+    #[serde(rename = "u")]
+    pub update_id: u64,
+}
+
+/// Keeps a local copy of the Bybit spot book from the snapshot and delta
+/// messages of the v5 `orderbook.50` topic, applied in update id order.
+/// Bybit closes connections that do not ping it, so a ping is sent every
+/// twenty seconds.
+pub struct Bybit {
+    socket: Socket,
+    symbol: String,
+    depth: usize,
+    silence_timeout: Duration,
+    ping: Option<Interval>,
+    /// When the last message was received. The pings interrupt the reads,
+    /// so the silence of the connection is checked on every ping instead.
+    last_message: Instant,
+    book: OrderBook,
+    /// Update id of the last snapshot or delta applied to `book`, or `None`
+    /// until a snapshot arrives.
+    last_update_id: Option<u64>,
+}
+
+impl Bybit {
+    pub fn new(symbol: String, config: &Config) -> Self {
+        Bybit {
+            socket: None,
+            symbol,
+            depth: config.max_depth,
+            silence_timeout: config.silence_timeout(),
+            ping: None,
+            last_message: Instant::now(),
+            book: OrderBook::default(),
+            last_update_id: None,
+        }
+    }
+
+    fn topic(&self) -> String {
+        format!("orderbook.{}.{}", BOOK_DEPTH, self.symbol.to_uppercase())
+    }
+
+    fn channel_message(&self, op: &str) -> String {
+        json!({
+            "op": op,
+            "args": [self.topic()],
+        }).to_string()
+    }
+
+    /// Applies a snapshot or a delta of the book. Returns whether the book
+    /// changed. Deltas before the first snapshot and deltas not newer than
+    /// the book are skipped. An update id of one marks a snapshot sent after
+    /// Bybit restarted the service.
+    fn apply_book(&mut self, kind: &str, book: BybitBook) -> Result<bool, OrderbookError> {
+        if kind == "snapshot" || book.update_id == 1 {
+            self.book.clear();
+        }
+        else {
+            match self.last_update_id {
+                Some(last_update_id) if book.update_id > last_update_id => (),
+                _ => return Ok(false),
+            }
+        }
+
+        for (price, size) in &book.bids {
+            self.book.update(Side::Bid, price, size)?;
+        }
+        for (price, size) in &book.asks {
+            self.book.update(Side::Ask, price, size)?;
+        }
+        self.book.truncate(BOOK_DEPTH);
+        self.last_update_id = Some(book.update_id);
+
+        Ok(true)
+    }
+
+    /// Applies the Bybit message `msg`. Returns whether the book changed.
+    /// Replies to requests, such as the pong of a ping, leave the book as
+    /// it is.
+    fn apply_message(&mut self, msg: &str) -> Result<bool, OrderbookError> {
+        let bybit_msg: BybitMsg = match serde_json::from_str(msg) {
+            Ok(bybit_msg) => bybit_msg,
+            Err(_) => {
+                return Err(OrderbookError::JsonParseError);
+            }
+        };
+
+        if bybit_msg.success == Some(false) {
+            println!("{} Error: {}", EXCHANGE_NAME, bybit_msg.ret_msg.unwrap_or_default());
+            return Err(OrderbookError::SubscriptionError);
+        }
+
+        match (bybit_msg.topic, bybit_msg.kind, bybit_msg.data) {
+            (Some(_), Some(kind), Some(book)) => self.apply_book(&kind, book),
+            _ => Ok(false),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Exchange for Bybit {
+    async fn connect(&mut self) -> Result<(), OrderbookError> {
+        self.socket = None;
+        self.book.clear();
+        self.last_update_id = None;
+
+        let mut socket = exchange::connect(WSS_BASE_ENDPOINT).await?;
+        exchange::send_text(&mut socket, self.channel_message("subscribe")).await?;
+        self.socket = Some(socket);
+        self.ping = Some(time::interval_at(Instant::now() + PING_INTERVAL, PING_INTERVAL));
+        self.last_message = Instant::now();
+
+        Ok(())
+    }
+
+    async fn close(&mut self) {
+        let unsubscribe_message = self.channel_message("unsubscribe");
+        let _ = exchange::send(&mut self.socket, unsubscribe_message).await;
+
+        exchange::close(&mut self.socket).await;
+        self.ping = None;
+    }
+
+    async fn stream(&mut self) -> Result<Option<Summary>, OrderbookError> {
+        loop {
+            let (socket, ping) = match (&mut self.socket, &mut self.ping) {
+                (Some(socket), Some(ping)) => (socket, ping),
+                _ => {
+                    return Err(OrderbookError::NoConnectionError);
+                }
+            };
+
+            let msg = tokio::select! {
+                msg = exchange::read_text(socket, self.silence_timeout) => Some(msg?),
+                _ = ping.tick() => None,
+            };
+
+            let msg = match msg {
+                Some(msg) => {
+                    self.last_message = Instant::now();
+                    msg
+                }
+                None if self.last_message.elapsed() >= self.silence_timeout => {
+                    return Err(OrderbookError::StaleConnectionError);
+                }
+                None => {
+                    let ping_message = json!({ "op": "ping" }).to_string();
+                    exchange::send_text(socket, ping_message).await?;
+                    continue;
+                }
+            };
+
+            if self.apply_message(&msg)? {
+                return Ok(Some(self.book.summarise(EXCHANGE_NAME, self.depth)));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_bybit_applies_recorded_orderbook_by_update_id() {
+    let recorded = include_str!("../tests/fixtures/bybit_orderbook.jsonl");
+    let mut bybit = Bybit::new(String::from("btcusdt"), &Config::default());
+    assert_eq!(bybit.topic(), "orderbook.50.BTCUSDT");
+
+    // Deltas wait for the first snapshot.
+    let delta = recorded.lines().nth(2).unwrap();
+    assert!(!bybit.apply_message(delta).unwrap());
+
+    let changes: Vec<bool> = recorded.lines()
+        .take(5)
+        .map(|msg| bybit.apply_message(msg).unwrap())
+        .collect();
+    // The second delta is not newer than the book, and the pong is ignored.
+    assert_eq!(changes, vec![false, true, true, false, false]);
+
+    let summary = bybit.book.summarise(EXCHANGE_NAME, 10);
+    assert_eq!(summary.bids.len(), 2);
+    assert_eq!(summary.bids[0].price, 16493.0);
+    assert_eq!(summary.asks[0].amount, 0.031);
+
+    // A restart of the service starts the book over.
+    let restart = recorded.lines().nth(5).unwrap();
+    assert!(bybit.apply_message(restart).unwrap());
+    let summary = bybit.book.summarise(EXCHANGE_NAME, 10);
+    assert_eq!(summary.bids.len(), 1);
+    assert_eq!(summary.bids[0].price, 16480.0);
+
+    let error = r#"{"success":false,"ret_msg":"Invalid symbol :[orderbook.50.BTCXYZ]","conn_id":"2324d924-aa4d-45b0-a858-7b8be29ab52b","req_id":"","op":"subscribe"}"#;
+    assert!(matches!(bybit.apply_message(error), Err(OrderbookError::SubscriptionError)));
+}
@@ -1,11 +1,314 @@
-use tokio::sync::mpsc;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use tokio::sync::{mpsc, Notify};
 use tonic::Status;
 
-use crate::orderbook_aggregator::Summary;
+use crate::aggregator::Aggregator;
+use crate::error::OrderbookError;
+use crate::orderbook_aggregator::{
+    ClientInfo,
+    ConflationPolicy,
+    Level,
+    Summary,
+    Symbol,
+};
+use crate::venue::VenueRegistry;
+
+/// Depth used when a client does not ask for a specific one.
+const DEFAULT_DEPTH: usize = 10;
+
+/// Queue size used with the queue policy when a client does not ask for a
+/// specific one.
+const DEFAULT_QUEUE_SIZE: usize = 16;
+
+pub type ClientId = u64;
+
+/// How updates pile up for a client that does not keep up with its symbol.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Conflation {
+    /// Only the latest update is kept.
+    Latest,
+    /// Up to this many updates are kept, dropping the oldest ones first.
+    Queue(usize),
+}
+
+/// What a client wants to see of the shared aggregate of its symbol.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubscriptionOptions {
+    pub depth: usize,
+    /// Exchanges to keep in the aggregate. Empty means all exchanges.
+    pub exchanges: Vec<String>,
+    pub min_interval: Duration,
+    pub conflation: Conflation,
+}
+
+impl SubscriptionOptions {
+    /// Builds the options requested in `request`, capping the depth to
+    /// `max_depth`. Fails if the request names an exchange that is not in
+    /// `venues`.
+    pub fn from_request(
+        request: &Symbol,
+        venues: &VenueRegistry,
+        max_depth: usize,
+    ) -> Result<Self, OrderbookError> {
+        let depth = match request.depth as usize {
+            0 => DEFAULT_DEPTH,
+            depth => depth,
+        };
+
+        let mut exchanges = Vec::new();
+        for requested in &request.exchanges {
+            let exchange = venues.find(requested)
+                .ok_or_else(|| {
+                    OrderbookError::UnknownExchangeError(requested.clone())
+                })?;
+            exchanges.push(String::from(exchange));
+        }
+
+        let conflation = match request.conflation() {
+            ConflationPolicy::Latest => Conflation::Latest,
+            ConflationPolicy::Queue => match request.queue_size as usize {
+                0 => Conflation::Queue(DEFAULT_QUEUE_SIZE),
+                queue_size => Conflation::Queue(queue_size),
+            },
+        };
+
+        Ok(SubscriptionOptions {
+            depth: depth.min(max_depth),
+            exchanges,
+            min_interval: Duration::from_millis(request.min_interval_ms as u64),
+            conflation,
+        })
+    }
+
+    /// Adds `summary` to the updates waiting in `queue` for the client,
+    /// dropping older updates as the conflation policy requires. Returns the
+    /// number of updates dropped.
+    pub fn conflate(&self, queue: &mut VecDeque<Summary>, summary: Summary) -> u64 {
+        let capacity = match self.conflation {
+            Conflation::Latest => 1,
+            Conflation::Queue(queue_size) => queue_size.max(1),
+        };
+
+        let mut dropped = 0;
+        while queue.len() >= capacity {
+            queue.pop_front();
+            dropped += 1;
+        }
+        queue.push_back(summary);
+
+        dropped
+    }
+
+    /// Narrows the shared `summary` down to the exchanges and depth of these
+    /// options.
+    pub fn apply(&self, summary: &Summary) -> Summary {
+        let select = |levels: &[Level]| -> Vec<Level> {
+            let selected = levels.iter()
+                .filter(|level| {
+                    self.exchanges.is_empty() ||
+                    self.exchanges.contains(&level.exchange)
+                })
+                .cloned()
+                .collect();
+
+            Aggregator::top(self.depth, selected)
+        };
+
+        let bids = select(&summary.bids);
+        let asks = select(&summary.asks);
+
+        Summary {
+            spread: Aggregator::spread(&bids, &asks),
+            bids,
+            asks,
+            symbol: summary.symbol.clone(),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Client {
-    pub sender: Option<mpsc::Sender<Result<Summary, Status>>>,
+    pub id: ClientId,
+    pub sender: mpsc::Sender<Result<Summary, Status>>,
     pub symbol: String,
+    pub options: SubscriptionOptions,
+    /// Notified to stop streaming to the client while it stays connected.
+    pub stop: Arc<Notify>,
+    /// Number of updates dropped because the client did not keep up.
+    pub dropped: Arc<AtomicU64>,
+}
+
+impl Client {
+    pub fn record_dropped(&self, count: u64) {
+        self.dropped.fetch_add(count, Ordering::Relaxed);
+    }
+}
+
+/// Keeps track of every client currently streaming from the server. Each
+/// client gets its own id and sender so that clients never interfere with
+/// one another.
+#[derive(Debug, Default)]
+pub struct ClientRegistry {
+    next_id: ClientId,
+    clients: HashMap<ClientId, Client>,
+}
+
+impl ClientRegistry {
+    /// Registers a new client streaming `symbol` with `options` through
+    /// `sender` and returns the registered client.
+    pub fn register(
+        &mut self,
+        symbol: String,
+        options: SubscriptionOptions,
+        sender: mpsc::Sender<Result<Summary, Status>>,
+    ) -> Client {
+        self.next_id += 1;
+
+        let client = Client {
+            id: self.next_id,
+            sender,
+            symbol,
+            options,
+            stop: Arc::new(Notify::new()),
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+        self.clients.insert(client.id, client.clone());
+
+        client
+    }
+
+    /// Removes the client with `id` from the registry, returning it if it was
+    /// registered.
+    pub fn unregister(&mut self, id: ClientId) -> Option<Client> {
+        self.clients.remove(&id)
+    }
+
+    /// Every registered client.
+    pub fn clients(&self) -> Vec<Client> {
+        self.clients.values().cloned().collect()
+    }
+
+    /// Describes every registered client, sorted by id.
+    pub fn list(&self) -> Vec<ClientInfo> {
+        let mut clients: Vec<ClientInfo> = self.clients.values()
+            .map(|client| {
+                let conflation = match client.options.conflation {
+                    Conflation::Latest => ConflationPolicy::Latest,
+                    Conflation::Queue(_) => ConflationPolicy::Queue,
+                };
+
+                ClientInfo {
+                    id: client.id,
+                    symbol: client.symbol.clone(),
+                    conflation: conflation as i32,
+                    dropped: client.dropped.load(Ordering::Relaxed),
+                }
+            })
+            .collect();
+        clients.sort_by_key(|client| client.id);
+
+        clients
+    }
+}
+
+#[cfg(test)]
+fn test_venues() -> VenueRegistry {
+    VenueRegistry::from_config(&crate::config::Config::default()).unwrap()
 }
 
+#[test]
+fn test_registry_keeps_clients_independent() {
+    let mut registry = ClientRegistry::default();
+    let (sender_a, _receiver_a) = mpsc::channel(1);
+    let (sender_b, _receiver_b) = mpsc::channel(1);
+    let options = SubscriptionOptions::from_request(
+        &Symbol::default(), &test_venues(), 50).unwrap();
+
+    let client_a = registry.register(String::from("btcusdt"), options.clone(), sender_a);
+    let client_b = registry.register(String::from("ethbtc"), options, sender_b);
+
+    assert_ne!(client_a.id, client_b.id);
+
+    let removed = registry.unregister(client_a.id).unwrap();
+    assert_eq!(removed.symbol, "btcusdt");
+    assert!(registry.unregister(client_a.id).is_none());
+
+    let remaining = registry.unregister(client_b.id).unwrap();
+    assert_eq!(remaining.symbol, "ethbtc");
+}
+
+#[test]
+fn test_options_select_exchanges_and_depth() {
+    let level = |exchange: &str, price: f64| Level {
+        exchange: String::from(exchange),
+        price,
+        amount: 1.0,
+    };
+
+    let summary = Summary {
+        spread: -1.0,
+        bids: vec![
+            level("Binance", 10.0),
+            level("Bitstamp", 9.0),
+            level("Binance", 8.0),
+            level("Bitstamp", 7.0),
+        ],
+        asks: vec![
+            level("Bitstamp", 11.0),
+            level("Binance", 12.0),
+            level("Bitstamp", 13.0),
+        ],
+        symbol: String::from("btcusdt"),
+    };
+
+    let request = Symbol {
+        symbol: String::from("btcusdt"),
+        depth: 2,
+        exchanges: vec![String::from("bitstamp")],
+        ..Symbol::default()
+    };
+    let options = SubscriptionOptions::from_request(&request, &test_venues(), 50).unwrap();
+    let view = options.apply(&summary);
+
+    assert_eq!(view.bids, vec![level("Bitstamp", 9.0), level("Bitstamp", 7.0)]);
+    assert_eq!(view.asks, vec![level("Bitstamp", 11.0), level("Bitstamp", 13.0)]);
+    assert_eq!(view.spread, -2.0);
+    assert_eq!(view.symbol, "btcusdt");
+
+    let request = Symbol {
+        exchanges: vec![String::from("Mtgox")],
+        ..request
+    };
+    assert!(SubscriptionOptions::from_request(&request, &test_venues(), 50).is_err());
+}
+
+#[test]
+fn test_conflation_drops_oldest_updates() {
+    let summary = |spread: f64| Summary {
+        spread,
+        ..Summary::default()
+    };
+
+    let latest = SubscriptionOptions::from_request(
+        &Symbol::default(), &test_venues(), 50).unwrap();
+    let mut queue = VecDeque::new();
+    assert_eq!(latest.conflate(&mut queue, summary(1.0)), 0);
+    assert_eq!(latest.conflate(&mut queue, summary(2.0)), 1);
+    assert_eq!(queue, vec![summary(2.0)]);
+
+    let request = Symbol {
+        conflation: ConflationPolicy::Queue as i32,
+        queue_size: 2,
+        ..Symbol::default()
+    };
+    let queued = SubscriptionOptions::from_request(&request, &test_venues(), 50).unwrap();
+    let mut queue = VecDeque::new();
+    assert_eq!(queued.conflate(&mut queue, summary(1.0)), 0);
+    assert_eq!(queued.conflate(&mut queue, summary(2.0)), 0);
+    assert_eq!(queued.conflate(&mut queue, summary(3.0)), 1);
+    assert_eq!(queue, vec![summary(2.0), summary(3.0)]);
+}
@@ -0,0 +1,186 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::book::{OrderBook, Side};
+use crate::config::Config;
+use crate::error::OrderbookError;
+use crate::exchange::{Exchange, self, Socket};
+use crate::orderbook_aggregator::Summary;
+
+pub static EXCHANGE_NAME: &str = "Coinbase";
+static WSS_BASE_ENDPOINT: &str = "wss://ws-feed.exchange.coinbase.com";
+static CHANNEL: &str = "level2_batch";
+
+/// The messages of the `level2_batch` channel. Other messages, such as the
+/// subscription acknowledgement, are ignored.
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+pub enum CoinbaseMsg {
+    #[serde(rename = "snapshot")]
+    Snapshot {
+        bids: Vec<(String, String)>,
+        asks: Vec<(String, String)>,
+    },
+    /// Changes to the book as `[side, price, size]`, where side is `buy` or
+    /// `sell` and a zero size removes the level.
+    #[serde(rename = "l2update")]
+    Update {
+        changes: Vec<(String, String, String)>,
+    },
+    #[serde(rename = "error")]
+    Error {
+        message: String,
+        #[serde(default)]
+        reason: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+/// Keeps a local copy of the Coinbase Exchange book from the snapshot and
+/// `l2update` messages of the `level2_batch` channel.
+pub struct Coinbase {
+    socket: Socket,
+    symbol: String,
+    depth: usize,
+    silence_timeout: Duration,
+    book: OrderBook,
+    /// Whether `book` holds the snapshot that the updates apply to.
+    is_synced: bool,
+}
+
+impl Coinbase {
+    pub fn new(symbol: String, config: &Config) -> Self {
+        Coinbase {
+            socket: None,
+            symbol,
+            depth: config.max_depth,
+            silence_timeout: config.silence_timeout(),
+            book: OrderBook::default(),
+            is_synced: false,
+        }
+    }
+
+    /// Coinbase's name for the product, such as `BTC-USD`.
+    fn product_id(&self) -> Result<String, OrderbookError> {
+        exchange::split_symbol(&self.symbol)
+            .map(|(base, quote)| format!("{}-{}", base, quote))
+            .ok_or_else(|| OrderbookError::UnknownSymbolError(self.symbol.clone()))
+    }
+
+    fn channel_message(&self, kind: &str) -> Result<String, OrderbookError> {
+        Ok(json!({
+            "type": kind,
+            "product_ids": [self.product_id()?],
+            "channels": [CHANNEL],
+        }).to_string())
+    }
+
+    /// Applies the Coinbase message `msg` to the book. Returns the summary
+    /// of the book when the message changed it.
+    fn apply_message(&mut self, msg: &str) -> Result<Option<Summary>, OrderbookError> {
+        let coinbase_msg: CoinbaseMsg = match serde_json::from_str(msg) {
+            Ok(coinbase_msg) => coinbase_msg,
+            Err(_) => {
+                return Err(OrderbookError::JsonParseError);
+            }
+        };
+
+        match coinbase_msg {
+            CoinbaseMsg::Snapshot { bids, asks } => {
+                self.book.clear();
+                for (price, size) in &bids {
+                    self.book.update(Side::Bid, price, size)?;
+                }
+                for (price, size) in &asks {
+                    self.book.update(Side::Ask, price, size)?;
+                }
+                self.is_synced = true;
+            }
+            CoinbaseMsg::Update { changes } if self.is_synced => {
+                for (side, price, size) in &changes {
+                    let side = match side.as_str() {
+                        "buy" => Side::Bid,
+                        "sell" => Side::Ask,
+                        _ => return Err(OrderbookError::JsonParseError),
+                    };
+                    self.book.update(side, price, size)?;
+                }
+            }
+            CoinbaseMsg::Error { message, reason } => {
+                println!("{} Error: {} {}", EXCHANGE_NAME, message, reason);
+                return Err(OrderbookError::SubscriptionError);
+            }
+            _ => return Ok(None),
+        }
+
+        Ok(Some(self.book.summarise(EXCHANGE_NAME, self.depth)))
+    }
+}
+
+#[tonic::async_trait]
+impl Exchange for Coinbase {
+    async fn connect(&mut self) -> Result<(), OrderbookError> {
+        self.socket = None;
+        self.book.clear();
+        self.is_synced = false;
+
+        let subscribe_message = self.channel_message("subscribe")?;
+
+        let mut socket = exchange::connect(WSS_BASE_ENDPOINT).await?;
+        exchange::send_text(&mut socket, subscribe_message).await?;
+        self.socket = Some(socket);
+
+        Ok(())
+    }
+
+    async fn close(&mut self) {
+        if let Ok(unsubscribe_message) = self.channel_message("unsubscribe") {
+            let _ = exchange::send(&mut self.socket, unsubscribe_message).await;
+        }
+
+        exchange::close(&mut self.socket).await;
+    }
+
+    async fn stream(&mut self) -> Result<Option<Summary>, OrderbookError> {
+        let socket = match &mut self.socket {
+            Some(socket) => socket,
+            None => {
+                return Err(OrderbookError::NoConnectionError);
+            }
+        };
+
+        let msg = exchange::read_text(socket, self.silence_timeout).await?;
+
+        self.apply_message(&msg)
+    }
+}
+
+#[test]
+fn test_coinbase_applies_recorded_level2_batch() {
+    let recorded = include_str!("../tests/fixtures/coinbase_level2_batch.jsonl");
+    let mut coinbase = Coinbase::new(String::from("btcusd"), &Config::default());
+    assert_eq!(coinbase.channel_message("subscribe").unwrap(), json!({
+        "type": "subscribe",
+        "product_ids": ["BTC-USD"],
+        "channels": ["level2_batch"],
+    }).to_string());
+
+    let summaries: Vec<Option<Summary>> = recorded.lines()
+        .map(|msg| coinbase.apply_message(msg).unwrap())
+        .collect();
+    assert!(summaries[0].is_none());
+
+    let summary = summaries[3].as_ref().unwrap();
+    let prices = |levels: &[crate::orderbook_aggregator::Level]| -> Vec<f64> {
+        levels.iter().map(|level| level.price).collect()
+    };
+    assert_eq!(prices(&summary.bids), vec![29410.40, 29410.10, 29409.52]);
+    assert_eq!(prices(&summary.asks), vec![29410.12, 29410.30, 29411.19]);
+    assert_eq!(summary.asks[0].amount, 0.083);
+    assert!(summary.bids.iter().all(|level| level.exchange == "Coinbase"));
+
+    assert!(coinbase.apply_message(r#"{"type":"error","message":"Failed to subscribe"}"#).is_err());
+}
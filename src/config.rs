@@ -0,0 +1,145 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::backoff::Backoff;
+use crate::error::OrderbookError;
+use crate::generic::GenericVenueConfig;
+use crate::venue::CONNECTORS;
+
+/// Environment variable holding the path of the JSON configuration file.
+static CONFIG_PATH_VAR: &str = "ORDERBOOK_CONFIG";
+
+/// Server configuration. Every field has a default so a configuration file
+/// only needs to list the values it wants to change.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Address the gRPC server listens on.
+    pub address: String,
+    /// How long a symbol pipeline keeps running after its last subscriber
+    /// has left, in milliseconds.
+    pub pipeline_grace_period_ms: u64,
+    /// Maximum number of levels per side that each exchange contributes to
+    /// the aggregate and that a client may ask for.
+    pub max_depth: usize,
+    /// How long a snapshot request waits for the first summary of a symbol
+    /// whose pipeline has just been started, in milliseconds.
+    pub snapshot_timeout_ms: u64,
+    /// How long the server waits for clients and pipelines to wind down on
+    /// shutdown, in milliseconds.
+    pub shutdown_timeout_ms: u64,
+    /// Delay before the first attempt to reconnect to an exchange, in
+    /// milliseconds. The delay doubles with every failed attempt.
+    pub reconnect_initial_backoff_ms: u64,
+    /// Longest delay between two attempts to reconnect to an exchange, in
+    /// milliseconds.
+    pub reconnect_max_backoff_ms: u64,
+    /// How long an exchange connection may stay silent before it is
+    /// considered stale and re-established, in milliseconds. A ping is sent
+    /// halfway through, so that a quiet but healthy connection answers.
+    pub silence_timeout_ms: u64,
+    /// Names of the exchanges to aggregate.
+    pub exchanges: Vec<String>,
+    /// Venues aggregated through the generic websocket connector, in
+    /// addition to `exchanges`.
+    pub venues: Vec<GenericVenueConfig>,
+    /// Base URL of the Binance REST API, from which the orderbook snapshots
+    /// are fetched.
+    pub binance_rest_url: String,
+    /// Base URL of the Bitstamp REST API, from which the orderbook snapshots
+    /// are fetched.
+    pub bitstamp_rest_url: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: String::from("127.0.0.1:8888"),
+            pipeline_grace_period_ms: 5000,
+            max_depth: 50,
+            snapshot_timeout_ms: 5000,
+            shutdown_timeout_ms: 5000,
+            reconnect_initial_backoff_ms: 500,
+            reconnect_max_backoff_ms: 30000,
+            silence_timeout_ms: 30000,
+            exchanges: CONNECTORS.iter()
+                .map(|(name, _)| String::from(*name))
+                .collect(),
+            venues: Vec::new(),
+            binance_rest_url: String::from("https://api.binance.com"),
+            bitstamp_rest_url: String::from("https://www.bitstamp.net"),
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration from the file named by `ORDERBOOK_CONFIG`.
+    /// The defaults are used when the variable is not set. Fails if the file
+    /// cannot be read or holds values the server cannot run with.
+    pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
+        let path = match std::env::var(CONFIG_PATH_VAR) {
+            Ok(path) => path,
+            Err(_) => return Ok(Config::default()),
+        };
+
+        let contents = std::fs::read_to_string(&path)?;
+        let config: Config = serde_json::from_str(&contents)?;
+        config.validate()?;
+        println!("Loaded configuration from {}", path);
+
+        Ok(config)
+    }
+
+    /// Checks the values that would keep the server from working. A silence
+    /// timeout of zero would report every connection as stale straight away,
+    /// so that exchanges are reconnected over and over.
+    fn validate(&self) -> Result<(), OrderbookError> {
+        if self.silence_timeout_ms == 0 {
+            return Err(OrderbookError::InvalidConfigError(
+                String::from("silence_timeout_ms must be greater than zero")));
+        }
+
+        Ok(())
+    }
+
+    pub fn pipeline_grace_period(&self) -> Duration {
+        Duration::from_millis(self.pipeline_grace_period_ms)
+    }
+
+    pub fn snapshot_timeout(&self) -> Duration {
+        Duration::from_millis(self.snapshot_timeout_ms)
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_millis(self.shutdown_timeout_ms)
+    }
+
+    pub fn silence_timeout(&self) -> Duration {
+        Duration::from_millis(self.silence_timeout_ms)
+    }
+
+    pub fn reconnect_backoff(&self) -> Backoff {
+        Backoff::new(
+            Duration::from_millis(self.reconnect_initial_backoff_ms),
+            Duration::from_millis(self.reconnect_max_backoff_ms),
+        )
+    }
+}
+
+#[test]
+fn test_config_uses_defaults_for_missing_fields() {
+    let config: Config = serde_json::from_str(
+        r#"{ "pipeline_grace_period_ms": 250 }"#
+    ).unwrap();
+
+    assert_eq!(config.address, "127.0.0.1:8888");
+    assert_eq!(config.pipeline_grace_period(), Duration::from_millis(250));
+}
+
+#[test]
+fn test_config_rejects_zero_silence_timeout() {
+    let config: Config = serde_json::from_str(r#"{ "silence_timeout_ms": 0 }"#).unwrap();
+    assert!(matches!(config.validate(), Err(OrderbookError::InvalidConfigError(_))));
+    assert!(Config::default().validate().is_ok());
+}
@@ -0,0 +1,149 @@
+use crate::orderbook_aggregator::{BookDelta, Level, Summary};
+
+/// Number of deltas sent between two full snapshots when the client does not
+/// ask for a specific interval.
+const DEFAULT_SNAPSHOT_INTERVAL: u64 = 100;
+
+/// Turns the successive summaries sent to one client into numbered deltas.
+/// The first message and every `snapshot_interval`-th message after it carry
+/// the full book, as does the message following a snapshot request.
+pub struct DeltaEncoder {
+    sequence: u64,
+    snapshot_interval: u64,
+    deltas_since_snapshot: u64,
+    snapshot_requested: bool,
+    last_summary: Option<Summary>,
+}
+
+impl DeltaEncoder {
+    pub fn new(snapshot_interval: u32) -> Self {
+        let snapshot_interval = match snapshot_interval {
+            0 => DEFAULT_SNAPSHOT_INTERVAL,
+            interval => interval as u64,
+        };
+
+        DeltaEncoder {
+            sequence: 0,
+            snapshot_interval,
+            deltas_since_snapshot: 0,
+            snapshot_requested: true,
+            last_summary: None,
+        }
+    }
+
+    /// Makes the next encoded message a full snapshot.
+    pub fn request_snapshot(&mut self) {
+        self.snapshot_requested = true;
+    }
+
+    /// Encodes `summary` as the next message of the stream, either as the
+    /// changes since the previously encoded summary or as a full snapshot.
+    pub fn encode(&mut self, summary: &Summary) -> BookDelta {
+        self.sequence += 1;
+
+        let is_snapshot = self.snapshot_requested ||
+            self.deltas_since_snapshot >= self.snapshot_interval;
+
+        let (bids, asks) = match (&self.last_summary, is_snapshot) {
+            (Some(last_summary), false) => (
+                diff_levels(&last_summary.bids, &summary.bids),
+                diff_levels(&last_summary.asks, &summary.asks),
+            ),
+            _ => (summary.bids.clone(), summary.asks.clone()),
+        };
+
+        if is_snapshot {
+            self.snapshot_requested = false;
+            self.deltas_since_snapshot = 0;
+        }
+        else {
+            self.deltas_since_snapshot += 1;
+        }
+        self.last_summary = Some(summary.clone());
+
+        BookDelta {
+            symbol: summary.symbol.clone(),
+            sequence: self.sequence,
+            snapshot: is_snapshot,
+            spread: summary.spread,
+            bids,
+            asks,
+        }
+    }
+}
+
+/// Levels of `current` that were added or changed since `previous`, followed
+/// by the levels of `previous` that are gone with their amount set to zero.
+/// Levels are identified by their exchange and price.
+fn diff_levels(previous: &[Level], current: &[Level]) -> Vec<Level> {
+    let same_level = |a: &Level, b: &Level| {
+        a.exchange == b.exchange && a.price == b.price
+    };
+
+    let changed = current.iter()
+        .filter(|level| !previous.contains(level))
+        .cloned();
+
+    let removed = previous.iter()
+        .filter(|level| !current.iter().any(|current| same_level(level, current)))
+        .map(|level| Level {
+            amount: 0.0,
+            ..level.clone()
+        });
+
+    changed.chain(removed).collect()
+}
+
+#[test]
+fn test_encoder_sends_changes_between_snapshots() {
+    let level = |exchange: &str, price: f64, amount: f64| Level {
+        exchange: String::from(exchange),
+        price,
+        amount,
+    };
+    let summary = |bids: Vec<Level>| Summary {
+        spread: 0.0,
+        bids,
+        asks: Vec::new(),
+        symbol: String::from("btcusdt"),
+    };
+
+    let mut encoder = DeltaEncoder::new(2);
+
+    let first = encoder.encode(&summary(vec![
+        level("Binance", 10.0, 1.0),
+        level("Bitstamp", 9.0, 2.0),
+    ]));
+    assert_eq!(first.sequence, 1);
+    assert!(first.snapshot);
+    assert_eq!(first.bids.len(), 2);
+
+    let second = encoder.encode(&summary(vec![
+        level("Binance", 10.0, 1.5),
+        level("Binance", 8.0, 1.0),
+    ]));
+    assert_eq!(second.sequence, 2);
+    assert!(!second.snapshot);
+    assert_eq!(second.bids, vec![
+        level("Binance", 10.0, 1.5),
+        level("Binance", 8.0, 1.0),
+        level("Bitstamp", 9.0, 0.0),
+    ]);
+
+    let third = encoder.encode(&summary(vec![
+        level("Binance", 10.0, 1.5),
+        level("Binance", 8.0, 1.0),
+    ]));
+    assert_eq!(third.sequence, 3);
+    assert!(!third.snapshot);
+    assert!(third.bids.is_empty());
+
+    let fourth = encoder.encode(&summary(vec![level("Binance", 10.0, 1.5)]));
+    assert!(fourth.snapshot);
+    assert_eq!(fourth.bids, vec![level("Binance", 10.0, 1.5)]);
+
+    encoder.request_snapshot();
+    let fifth = encoder.encode(&summary(vec![level("Binance", 10.0, 1.5)]));
+    assert_eq!(fifth.sequence, 5);
+    assert!(fifth.snapshot);
+}
@@ -1,9 +1,23 @@
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum OrderbookError {
     JsonParseError,
+    ConnectError(String),
     NoConnectionError,
     SocketReadError,
     SubscriptionError,
+    UnknownExchangeError(String),
+    SnapshotUnavailableError(String),
+    ShuttingDownError,
+    ExchangeSnapshotError(String),
+    UnknownSymbolError(String),
+    ChecksumError,
+    SequenceGapError,
+    DuplicateExchangeError(String),
+    StaleConnectionError,
+    ConnectionLostError,
+    ExchangeError(String),
+    InvalidConfigError(String),
 }
 
 impl std::error::Error for OrderbookError {}
@@ -14,6 +28,9 @@ fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         OrderbookError::JsonParseError => {
             write!(f, "Error when parsing json data")
         }
+        OrderbookError::ConnectError(endpoint) => {
+            write!(f, "Could not connect to {}", endpoint)
+        },
         OrderbookError::NoConnectionError => {
             write!(f, "No client connection exists")
         },
@@ -23,6 +40,57 @@ fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         OrderbookError::SubscriptionError => {
             write!(f, "Error subscribing to exchange orderbook")
         },
+        OrderbookError::UnknownExchangeError(exchange) => {
+            write!(f, "Unknown exchange {}", exchange)
+        },
+        OrderbookError::SnapshotUnavailableError(symbol) => {
+            write!(f, "No orderbook available for symbol {}", symbol)
+        },
+        OrderbookError::ShuttingDownError => {
+            write!(f, "Server is shutting down")
+        },
+        OrderbookError::ExchangeSnapshotError(url) => {
+            write!(f, "Could not fetch the orderbook snapshot from {}", url)
+        },
+        OrderbookError::UnknownSymbolError(symbol) => {
+            write!(f, "Symbol {} is not traded on the exchange", symbol)
+        },
+        OrderbookError::ChecksumError => {
+            write!(f, "Orderbook checksum does not match the exchange's")
+        },
+        OrderbookError::SequenceGapError => {
+            write!(f, "Orderbook updates were missed")
+        },
+        OrderbookError::DuplicateExchangeError(exchange) => {
+            write!(f, "Exchange {} is configured more than once", exchange)
+        },
+        OrderbookError::StaleConnectionError => {
+            write!(f, "Nothing received from the exchange within the silence timeout")
+        },
+        OrderbookError::ConnectionLostError => {
+            write!(f, "Connection to the exchange was lost")
+        },
+        OrderbookError::ExchangeError(message) => {
+            write!(f, "Exchange reported an error: {}", message)
+        },
+        OrderbookError::InvalidConfigError(reason) => {
+            write!(f, "Invalid configuration: {}", reason)
+        },
     }
 }
+}
+
+impl From<OrderbookError> for tonic::Status {
+    fn from(error: OrderbookError) -> Self {
+        match error {
+            OrderbookError::UnknownExchangeError(_) => {
+                tonic::Status::invalid_argument(error.to_string())
+            },
+            OrderbookError::SnapshotUnavailableError(_) |
+            OrderbookError::ShuttingDownError => {
+                tonic::Status::unavailable(error.to_string())
+            },
+            _ => tonic::Status::internal(error.to_string()),
+        }
+    }
 }
\ No newline at end of file
@@ -1,38 +1,208 @@
-use tungstenite::{WebSocket, stream::MaybeTlsStream};
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio::time;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, tungstenite::{self, Message}};
 use url::Url;
 
 use crate::{orderbook_aggregator::Summary, error::OrderbookError};
 
-pub type Socket = Option<WebSocket<MaybeTlsStream<std::net::TcpStream>>>;
+/// How long to wait for an exchange to acknowledge closing the connection.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Quote currencies a symbol may end with. Longer names come first so that
+/// `usdt` is not mistaken for `usd`.
+static QUOTE_CURRENCIES: [&str; 9] = [
+    "usdt", "usdc", "busd", "usd", "eur", "gbp", "jpy", "btc", "eth",
+];
+
+pub type WebSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+pub type Socket = Option<WebSocket>;
 
-pub trait Exchange {
-    fn new(symbol: String) -> Self;
+/// Connection to the orderbook of one symbol on one exchange. Connectors are
+/// built by name through the venue registry and used as `dyn Exchange`.
+#[tonic::async_trait]
+pub trait Exchange: Send {
+    /// Connects to the exchange and subscribes to the orderbook of the
+    /// symbol, replacing any previous connection. The orderbook state kept
+    /// from a previous connection is discarded, so the book is rebuilt from
+    /// what the exchange sends on the new connection.
+    async fn connect(&mut self) -> Result<(), OrderbookError>;
 
-    fn stream(&mut self) -> Result<Option<Summary>, OrderbookError>;
+    /// Closes the connection to the exchange, unsubscribing first where the
+    /// exchange expects it.
+    async fn close(&mut self);
+
+    /// Waits for the next orderbook update of the exchange. `None` is
+    /// returned when the update does not change the book.
+    async fn stream(&mut self) -> Result<Option<Summary>, OrderbookError>;
+
+    /// Number of times the book was found out of sync with the exchange,
+    /// through missed or out of order updates or a checksum mismatch, and
+    /// synced again. Exchanges whose updates cannot be checked report none.
+    fn gaps(&self) -> u64 {
+        0
+    }
+}
+
+/// Splits `symbol` into its base and quote currencies, in upper case, for
+/// exchanges that name a pair by both currencies. `None` is returned when the
+/// symbol does not end with a known quote currency.
+pub fn split_symbol(symbol: &str) -> Option<(String, String)> {
+    let symbol = symbol.to_lowercase();
+
+    QUOTE_CURRENCIES.iter()
+        .find(|quote| symbol.len() > quote.len() && symbol.ends_with(*quote))
+        .map(|quote| {
+            let (base, quote) = symbol.split_at(symbol.len() - quote.len());
+            (base.to_uppercase(), quote.to_uppercase())
+        })
 }
 
 /// Connect to the exchange specified by the websocket endpoint.
 /// If connection is successful a tcp streaming websocket is returned to the
-/// caller. Otherwise `None` is returned.
-pub fn connect(ws_endpoint: &str) ->
-    Option<WebSocket<MaybeTlsStream<std::net::TcpStream>>> {
-    let url = match Url::parse(&ws_endpoint) {
+/// caller. Otherwise an error naming the endpoint is returned.
+pub async fn connect(ws_endpoint: &str) -> Result<WebSocket, OrderbookError> {
+    let url = match Url::parse(ws_endpoint) {
         Ok(url) => url,
         _ => {
             println!("Could not parse the WSS endpoint {}", ws_endpoint);
-            return None;
+            return Err(OrderbookError::ConnectError(String::from(ws_endpoint)));
         }
     };
 
-    let (socket, _) = match tungstenite::client::connect(url.clone()) {
+    let (socket, _) = match tokio_tungstenite::connect_async(url.clone()).await {
         Ok(result) => result,
         _ => {
             println!("Could not connect to URL: {}", url);
-            return None;
+            return Err(OrderbookError::ConnectError(String::from(ws_endpoint)));
         }
     };
 
     println!("Connected to Websocket URL {}", url);
 
-    Some(socket)
-}
\ No newline at end of file
+    Ok(socket)
+}
+
+/// Waits for the next text message on `socket`. Pings of the exchange are
+/// answered with a pong. When nothing is received for half of
+/// `silence_timeout` the exchange is pinged, and when nothing is received
+/// for the whole of it the connection is reported as stale, which catches
+/// connections that went away without being closed.
+pub async fn read_text(
+    socket: &mut WebSocket,
+    silence_timeout: Duration,
+) -> Result<String, OrderbookError> {
+    let mut is_pinged = false;
+
+    loop {
+        let msg = match time::timeout(silence_timeout / 2, socket.next()).await {
+            Ok(msg) => msg,
+            Err(_) if !is_pinged => {
+                socket.send(Message::Ping(Vec::new())).await
+                    .map_err(|_| OrderbookError::ConnectionLostError)?;
+                is_pinged = true;
+                continue;
+            }
+            Err(_) => return Err(OrderbookError::StaleConnectionError),
+        };
+        is_pinged = false;
+
+        match msg {
+            Some(Ok(Message::Text(msg))) => return Ok(msg),
+            Some(Ok(Message::Ping(payload))) => {
+                socket.send(Message::Pong(payload)).await
+                    .map_err(|_| OrderbookError::ConnectionLostError)?;
+            }
+            Some(Ok(Message::Pong(_))) => (),
+            None |
+            Some(Ok(Message::Close(_))) |
+            Some(Err(tungstenite::Error::ConnectionClosed)) => {
+                return Err(OrderbookError::ConnectionLostError);
+            }
+            _ => return Err(OrderbookError::SocketReadError),
+        }
+    }
+}
+
+/// Sends `msg` as a text message on `socket`. Exchanges are only ever sent
+/// subscription messages, so a failure is reported as a subscription error.
+pub async fn send_text(socket: &mut WebSocket, msg: String) -> Result<(), OrderbookError> {
+    socket.send(Message::Text(msg)).await
+        .map_err(|_| OrderbookError::SubscriptionError)
+}
+
+/// Sends `msg` as a text message on the connection `socket`, failing when
+/// the connection is not open.
+pub async fn send(socket: &mut Socket, msg: String) -> Result<(), OrderbookError> {
+    match socket {
+        Some(socket) => send_text(socket, msg).await,
+        None => Err(OrderbookError::NoConnectionError),
+    }
+}
+
+/// Leaves a channel and joins it again on the connection `socket`, for
+/// exchanges that start a subscription over with a snapshot of the book.
+pub async fn resubscribe(
+    socket: &mut Socket,
+    unsubscribe_message: Option<String>,
+    subscribe_message: String,
+) -> Result<(), OrderbookError> {
+    if let Some(unsubscribe_message) = unsubscribe_message {
+        send(socket, unsubscribe_message).await?;
+    }
+    send(socket, subscribe_message).await
+}
+
+/// Closes `socket` and waits for the exchange to acknowledge the close, so
+/// that the exchange sees a clean disconnect.
+pub async fn close(socket: &mut Socket) {
+    if let Some(mut socket) = socket.take() {
+        if socket.close(None).await.is_err() {
+            return;
+        }
+
+        // Messages already in flight are read and discarded until the close
+        // handshake completes.
+        let _ = tokio::time::timeout(CLOSE_TIMEOUT, async {
+            while let Some(Ok(_)) = socket.next().await {}
+        }).await;
+    }
+}
+
+#[test]
+fn test_split_symbol_finds_quote_currency() {
+    assert_eq!(
+        split_symbol("btcusdt"),
+        Some((String::from("BTC"), String::from("USDT"))));
+    assert_eq!(
+        split_symbol("ETHBTC"),
+        Some((String::from("ETH"), String::from("BTC"))));
+    assert_eq!(split_symbol("usd"), None);
+    assert_eq!(split_symbol("btcxyz"), None);
+}
+
+#[tokio::test]
+async fn test_read_text_answers_pings_and_detects_silence() {
+    // A local stub exchange that pings, sends one message and then goes
+    // silent without closing the connection.
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = format!("ws://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+        socket.send(Message::Ping(b"hb".to_vec())).await.unwrap();
+        assert_eq!(socket.next().await.unwrap().unwrap(), Message::Pong(b"hb".to_vec()));
+        socket.send(Message::Text(String::from("book"))).await.unwrap();
+        time::sleep(Duration::from_secs(2)).await;
+    });
+
+    let silence_timeout = Duration::from_millis(200);
+    let mut socket = connect(&endpoint).await.unwrap();
+    assert_eq!(read_text(&mut socket, silence_timeout).await.unwrap(), "book");
+    assert!(matches!(
+        read_text(&mut socket, silence_timeout).await,
+        Err(OrderbookError::StaleConnectionError)));
+}
@@ -0,0 +1,262 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::book::{OrderBook, Side};
+use crate::config::Config;
+use crate::error::OrderbookError;
+use crate::exchange::{Exchange, self, Socket};
+use crate::orderbook_aggregator::Summary;
+
+/// Where the price or the amount sits in a level of the payload: at an
+/// index of a level array, or under a key of a level object.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum LevelField {
+    Index(usize),
+    Key(String),
+}
+
+/// How prices and amounts are written in the payload.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueFormat {
+    /// As strings, such as `"29410.11"`.
+    String,
+    /// As numbers, such as `29410.11`.
+    Number,
+}
+
+/// What the levels of a message stand for.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BookMode {
+    /// Every message holds the whole book.
+    Snapshot,
+    /// Every message holds the levels that changed, and a zero amount
+    /// removes a level. The first message is expected to hold the whole
+    /// book.
+    Diff,
+}
+
+/// A venue read through the generic websocket connector, described in the
+/// configuration instead of in a module of its own. The endpoint and the
+/// strings of the subscribe message may contain the placeholders `{symbol}`,
+/// `{base}` and `{quote}` in lower case, or `{SYMBOL}`, `{BASE}` and
+/// `{QUOTE}` in upper case.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct GenericVenueConfig {
+    /// Exchange name the levels of the venue are tagged with.
+    pub name: String,
+    /// Websocket endpoint template.
+    pub endpoint: String,
+    /// Message sent once connected, if the venue needs one.
+    #[serde(default)]
+    pub subscribe: Option<Value>,
+    /// JSON pointer to the bids of a message, such as `/data/bids`.
+    pub bids: String,
+    /// JSON pointer to the asks of a message.
+    pub asks: String,
+    #[serde(default = "default_price_field")]
+    pub price: LevelField,
+    #[serde(default = "default_amount_field")]
+    pub amount: LevelField,
+    #[serde(default = "default_value_format")]
+    pub values: ValueFormat,
+    #[serde(default = "default_book_mode")]
+    pub mode: BookMode,
+}
+
+fn default_price_field() -> LevelField {
+    LevelField::Index(0)
+}
+
+fn default_amount_field() -> LevelField {
+    LevelField::Index(1)
+}
+
+fn default_value_format() -> ValueFormat {
+    ValueFormat::String
+}
+
+fn default_book_mode() -> BookMode {
+    BookMode::Snapshot
+}
+
+/// Keeps the book of a venue described by a `GenericVenueConfig`. Messages
+/// without bids or asks at the configured pointers, such as subscription
+/// acknowledgements, are ignored.
+pub struct Generic {
+    socket: Socket,
+    symbol: String,
+    venue: Arc<GenericVenueConfig>,
+    depth: usize,
+    silence_timeout: Duration,
+    book: OrderBook,
+}
+
+impl Generic {
+    pub fn new(symbol: String, venue: Arc<GenericVenueConfig>, config: &Config) -> Self {
+        Generic {
+            socket: None,
+            symbol,
+            venue,
+            depth: config.max_depth,
+            silence_timeout: config.silence_timeout(),
+            book: OrderBook::default(),
+        }
+    }
+
+    /// Replaces the placeholders of `template` with the symbol.
+    fn fill(&self, template: &str) -> String {
+        let (base, quote) = exchange::split_symbol(&self.symbol).unwrap_or_default();
+
+        template
+            .replace("{symbol}", &self.symbol.to_lowercase())
+            .replace("{SYMBOL}", &self.symbol.to_uppercase())
+            .replace("{base}", &base.to_lowercase())
+            .replace("{BASE}", &base)
+            .replace("{quote}", &quote.to_lowercase())
+            .replace("{QUOTE}", &quote)
+    }
+
+    /// Replaces the placeholders of every string in `template`.
+    fn fill_value(&self, template: &Value) -> Value {
+        match template {
+            Value::String(text) => Value::String(self.fill(text)),
+            Value::Array(values) => {
+                Value::Array(values.iter().map(|value| self.fill_value(value)).collect())
+            }
+            Value::Object(fields) => Value::Object(fields.iter()
+                .map(|(key, value)| (key.clone(), self.fill_value(value)))
+                .collect()),
+            value => value.clone(),
+        }
+    }
+
+    /// Reads the price or the amount of `level` as text.
+    fn field(&self, level: &Value, field: &LevelField) -> Result<String, OrderbookError> {
+        let value = match field {
+            LevelField::Index(index) => level.get(index),
+            LevelField::Key(key) => level.get(key),
+        };
+
+        match (value, self.venue.values) {
+            (Some(Value::String(text)), ValueFormat::String) => Ok(text.clone()),
+            (Some(Value::Number(number)), ValueFormat::Number) => Ok(number.to_string()),
+            _ => Err(OrderbookError::JsonParseError),
+        }
+    }
+
+    /// Applies the message `msg` to the book. Returns the summary of the
+    /// book when the message carried levels.
+    fn apply_message(&mut self, msg: &str) -> Result<Option<Summary>, OrderbookError> {
+        let value: Value = match serde_json::from_str(msg) {
+            Ok(value) => value,
+            Err(_) => {
+                return Err(OrderbookError::JsonParseError);
+            }
+        };
+
+        let bids = value.pointer(&self.venue.bids);
+        let asks = value.pointer(&self.venue.asks);
+        if bids.is_none() && asks.is_none() {
+            return Ok(None);
+        }
+
+        if self.venue.mode == BookMode::Snapshot {
+            self.book.clear();
+        }
+
+        for (side, levels) in [(Side::Bid, bids), (Side::Ask, asks)] {
+            let levels = match levels {
+                Some(Value::Array(levels)) => levels,
+                Some(_) => return Err(OrderbookError::JsonParseError),
+                None => continue,
+            };
+
+            for level in levels {
+                let price = self.field(level, &self.venue.price)?;
+                let amount = self.field(level, &self.venue.amount)?;
+                self.book.update(side, &price, &amount)?;
+            }
+        }
+
+        Ok(Some(self.book.summarise(&self.venue.name, self.depth)))
+    }
+}
+
+#[tonic::async_trait]
+impl Exchange for Generic {
+    async fn connect(&mut self) -> Result<(), OrderbookError> {
+        self.socket = None;
+        self.book.clear();
+
+        let endpoint = self.fill(&self.venue.endpoint);
+        let mut socket = exchange::connect(&endpoint).await?;
+        if let Some(subscribe) = &self.venue.subscribe {
+            let subscribe_message = self.fill_value(subscribe).to_string();
+            exchange::send_text(&mut socket, subscribe_message).await?;
+        }
+        self.socket = Some(socket);
+
+        Ok(())
+    }
+
+    async fn close(&mut self) {
+        exchange::close(&mut self.socket).await;
+    }
+
+    async fn stream(&mut self) -> Result<Option<Summary>, OrderbookError> {
+        let socket = match &mut self.socket {
+            Some(socket) => socket,
+            None => {
+                return Err(OrderbookError::NoConnectionError);
+            }
+        };
+
+        let msg = exchange::read_text(socket, self.silence_timeout).await?;
+
+        self.apply_message(&msg)
+    }
+}
+
+#[test]
+fn test_generic_venue_reads_configured_payload() {
+    use serde_json::json;
+
+    let venue: GenericVenueConfig = serde_json::from_value(json!({
+        "name": "Example",
+        "endpoint": "wss://ws.example.com/v1/book",
+        "subscribe": {
+            "type": "subscribe",
+            "subscriptions": [{ "name": "l2", "symbols": ["{BASE}{QUOTE}"] }],
+        },
+        "bids": "/book/bids",
+        "asks": "/book/asks",
+        "price": "px",
+        "amount": "qty",
+        "values": "number",
+        "mode": "diff",
+    })).unwrap();
+
+    let mut generic = Generic::new(String::from("btcusd"), Arc::new(venue), &Config::default());
+    let subscribe = generic.fill_value(generic.venue.subscribe.as_ref().unwrap());
+    assert_eq!(subscribe["subscriptions"][0]["symbols"][0], "BTCUSD");
+
+    assert!(generic.apply_message(r#"{"type":"heartbeat"}"#).unwrap().is_none());
+    generic.apply_message(r#"{"book":{"bids":[{"px":100.5,"qty":2}],"asks":[{"px":101,"qty":1}]}}"#).unwrap();
+    let summary = generic.apply_message(r#"{"book":{"bids":[{"px":100.5,"qty":0},{"px":100,"qty":3}]}}"#)
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(summary.bids.len(), 1);
+    assert_eq!(summary.bids[0].price, 100.0);
+    assert_eq!(summary.bids[0].exchange, "Example");
+    assert_eq!(summary.asks[0].price, 101.0);
+
+    // Strings are refused where numbers are expected.
+    assert!(generic.apply_message(r#"{"book":{"bids":[{"px":"100","qty":"1"}]}}"#).is_err());
+}
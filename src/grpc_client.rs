@@ -16,6 +16,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let request = tonic::Request::new(
         Symbol {
             symbol: String::from("btcusdt"),
+            depth: 10,
+            ..Symbol::default()
         },
     );
 
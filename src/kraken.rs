@@ -0,0 +1,352 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::book::{OrderBook, Side};
+use crate::config::Config;
+use crate::error::OrderbookError;
+use crate::exchange::{Exchange, self, Socket};
+use crate::order::Quote;
+use crate::orderbook_aggregator::Summary;
+
+pub static EXCHANGE_NAME: &str = "Kraken";
+static WSS_BASE_ENDPOINT: &str = "wss://ws.kraken.com/v2";
+
+/// Book depths Kraken accepts in a subscription.
+static BOOK_DEPTHS: [usize; 5] = [10, 25, 100, 500, 1000];
+
+/// Number of levels per side covered by Kraken's book checksum.
+const CHECKSUM_DEPTH: usize = 10;
+
+/// Any message sent by Kraken. Channel messages carry a `channel`, while
+/// replies to requests carry a `method` and whether it succeeded.
+#[derive(Deserialize)]
+pub struct KrakenMsg {
+    #[serde(default)]
+    pub channel: String,
+    #[serde(rename = "type", default)]
+    pub kind: String,
+    #[serde(default)]
+    pub data: serde_json::Value,
+    pub success: Option<bool>,
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct KrakenLevel {
+    pub price: f64,
+    pub qty: f64,
+}
+
+/// Snapshot or update of the book on the `book` channel.
+#[derive(Deserialize)]
+pub struct KrakenBook {
+    #[serde(default)]
+    pub bids: Vec<KrakenLevel>,
+    #[serde(default)]
+    pub asks: Vec<KrakenLevel>,
+    pub checksum: u32,
+}
+
+/// The pairs listed on the `instrument` channel.
+#[derive(Deserialize)]
+pub struct KrakenInstruments {
+    #[serde(default)]
+    pub pairs: Vec<KrakenPair>,
+}
+
+#[derive(Deserialize)]
+pub struct KrakenPair {
+    pub symbol: String,
+    pub price_precision: usize,
+    pub qty_precision: usize,
+}
+
+/// Keeps a local copy of the Kraken book from the snapshot and updates of
+/// the websocket v2 `book` channel. Every update is verified against the
+/// CRC32 checksum Kraken sends with it, using the precisions published on
+/// the `instrument` channel, and the book is subscribed to again when the
+/// checksum does not match.
+pub struct Kraken {
+    socket: Socket,
+    symbol: String,
+    depth: usize,
+    silence_timeout: Duration,
+    book: OrderBook,
+    /// Whether `book` holds a snapshot that the updates apply to.
+    is_synced: bool,
+    /// Number of times the book was found out of sync and synced again.
+    gaps: u64,
+    /// Price and amount precisions of the pair, once Kraken has sent them.
+    precision: Option<(usize, usize)>,
+}
+
+impl Kraken {
+    pub fn new(symbol: String, config: &Config) -> Self {
+        let depth = BOOK_DEPTHS.iter()
+            .copied()
+            .find(|depth| *depth >= config.max_depth)
+            .unwrap_or(BOOK_DEPTHS[BOOK_DEPTHS.len() - 1]);
+
+        Kraken {
+            socket: None,
+            symbol,
+            depth,
+            silence_timeout: config.silence_timeout(),
+            book: OrderBook::default(),
+            is_synced: false,
+            gaps: 0,
+            precision: None,
+        }
+    }
+
+    /// Kraken's name for the pair, such as `BTC/USD`.
+    fn pair(&self) -> Result<String, OrderbookError> {
+        exchange::split_symbol(&self.symbol)
+            .map(|(base, quote)| format!("{}/{}", base, quote))
+            .ok_or_else(|| OrderbookError::UnknownSymbolError(self.symbol.clone()))
+    }
+
+    fn channel_message(&self, method: &str, channel: &str) -> Result<String, OrderbookError> {
+        let mut params = json!({
+            "channel": channel,
+        });
+        if channel == "book" {
+            params["symbol"] = json!([self.pair()?]);
+            params["depth"] = json!(self.depth);
+        }
+
+        Ok(json!({
+            "method": method,
+            "params": params,
+        }).to_string())
+    }
+
+    /// Subscribes to the `book` channel again, clearing the book until the
+    /// new snapshot is picked up by `apply_book`. The precisions of the pair
+    /// are kept, since the `instrument` channel stays subscribed.
+    async fn resubscribe(&mut self) -> Result<(), OrderbookError> {
+        self.book.clear();
+        self.is_synced = false;
+
+        let unsubscribe_message = self.channel_message("unsubscribe", "book")?;
+        let subscribe_message = self.channel_message("subscribe", "book")?;
+        exchange::resubscribe(
+            &mut self.socket,
+            Some(unsubscribe_message),
+            subscribe_message,
+        ).await
+    }
+
+    /// Records the precisions of the pair if `instruments` lists it. A
+    /// snapshot of the instruments that does not list the pair means Kraken
+    /// does not trade it.
+    fn apply_instruments(
+        &mut self,
+        kind: &str,
+        instruments: KrakenInstruments,
+    ) -> Result<(), OrderbookError> {
+        let pair = self.pair()?;
+
+        match instruments.pairs.iter().find(|known| known.symbol == pair) {
+            Some(pair) => {
+                self.precision = Some((pair.price_precision, pair.qty_precision));
+            }
+            None if kind == "snapshot" => {
+                return Err(OrderbookError::UnknownSymbolError(self.symbol.clone()));
+            }
+            None => (),
+        }
+
+        Ok(())
+    }
+
+    /// Applies a snapshot or an update of the book and verifies the
+    /// checksum of the result. Returns whether the book changed, and fails
+    /// with a checksum error when the book no longer matches Kraken's. The
+    /// book is kept but not reported as changed until the precisions of the
+    /// pair are known, since it cannot be verified before.
+    fn apply_book(&mut self, kind: &str, book: KrakenBook) -> Result<bool, OrderbookError> {
+        match kind {
+            "snapshot" => {
+                self.book.clear();
+                self.is_synced = true;
+            }
+            _ if !self.is_synced => return Ok(false),
+            _ => (),
+        }
+
+        for bid in &book.bids {
+            self.book.update_quote(Side::Bid, &Quote { price: bid.price, amount: bid.qty });
+        }
+        for ask in &book.asks {
+            self.book.update_quote(Side::Ask, &Quote { price: ask.price, amount: ask.qty });
+        }
+        self.book.truncate(self.depth);
+
+        let (price_precision, qty_precision) = match self.precision {
+            Some(precision) => precision,
+            None => return Ok(false),
+        };
+        if checksum(&self.book, price_precision, qty_precision) != book.checksum {
+            self.is_synced = false;
+            return Err(OrderbookError::ChecksumError);
+        }
+
+        Ok(true)
+    }
+
+    /// Applies the Kraken message `msg`. Returns whether the book changed,
+    /// and fails with a checksum error when the book no longer matches
+    /// Kraken's.
+    fn apply_message(&mut self, msg: &str) -> Result<bool, OrderbookError> {
+        let kraken_msg: KrakenMsg = match serde_json::from_str(msg) {
+            Ok(kraken_msg) => kraken_msg,
+            Err(_) => {
+                return Err(OrderbookError::JsonParseError);
+            }
+        };
+
+        if kraken_msg.success == Some(false) {
+            println!(
+                "{} Error: {}",
+                EXCHANGE_NAME, kraken_msg.error.unwrap_or_default());
+            return Err(OrderbookError::SubscriptionError);
+        }
+
+        match kraken_msg.channel.as_str() {
+            "instrument" => {
+                let instruments = serde_json::from_value(kraken_msg.data)
+                    .map_err(|_| OrderbookError::JsonParseError)?;
+                self.apply_instruments(&kraken_msg.kind, instruments)?;
+                Ok(false)
+            }
+            "book" => {
+                let books: Vec<KrakenBook> = serde_json::from_value(kraken_msg.data)
+                    .map_err(|_| OrderbookError::JsonParseError)?;
+
+                let mut is_changed = false;
+                for book in books {
+                    is_changed |= self.apply_book(&kraken_msg.kind, book)?;
+                }
+                Ok(is_changed)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+/// Kraken's checksum of `book`: the CRC32 of the price and amount of the top
+/// ten asks followed by the top ten bids, each written with the precision of
+/// the pair without its decimal point and leading zeros.
+fn checksum(book: &OrderBook, price_precision: usize, qty_precision: usize) -> u32 {
+    let field = |value: f64, precision: usize| {
+        let text = format!("{:.*}", precision, value).replace('.', "");
+        String::from(text.trim_start_matches('0'))
+    };
+
+    let mut hasher = crc32fast::Hasher::new();
+    let levels = book.asks().take(CHECKSUM_DEPTH)
+        .chain(book.bids().take(CHECKSUM_DEPTH));
+    for level in levels {
+        hasher.update(field(level.price, price_precision).as_bytes());
+        hasher.update(field(level.amount, qty_precision).as_bytes());
+    }
+
+    hasher.finalize()
+}
+
+#[tonic::async_trait]
+impl Exchange for Kraken {
+    async fn connect(&mut self) -> Result<(), OrderbookError> {
+        self.socket = None;
+        self.book.clear();
+        self.is_synced = false;
+
+        let instrument_message = self.channel_message("subscribe", "instrument")?;
+        let book_message = self.channel_message("subscribe", "book")?;
+
+        let mut socket = exchange::connect(WSS_BASE_ENDPOINT).await?;
+        exchange::send_text(&mut socket, instrument_message).await?;
+        exchange::send_text(&mut socket, book_message).await?;
+        self.socket = Some(socket);
+
+        Ok(())
+    }
+
+    async fn close(&mut self) {
+        if let Ok(unsubscribe_message) = self.channel_message("unsubscribe", "book") {
+            let _ = exchange::send(&mut self.socket, unsubscribe_message).await;
+        }
+
+        exchange::close(&mut self.socket).await;
+    }
+
+    async fn stream(&mut self) -> Result<Option<Summary>, OrderbookError> {
+        loop {
+            let socket = match &mut self.socket {
+                Some(socket) => socket,
+                None => {
+                    return Err(OrderbookError::NoConnectionError);
+                }
+            };
+
+            let msg = exchange::read_text(socket, self.silence_timeout).await?;
+
+            match self.apply_message(&msg) {
+                Ok(true) => {
+                    return Ok(Some(self.book.summarise(EXCHANGE_NAME, self.depth)));
+                }
+                Ok(false) => (),
+                Err(OrderbookError::ChecksumError) => {
+                    println!(
+                        "{} {} checksum mismatch, resubscribing",
+                        EXCHANGE_NAME, self.symbol);
+                    self.gaps += 1;
+                    self.resubscribe().await?;
+                    return Ok(None);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn gaps(&self) -> u64 {
+        self.gaps
+    }
+}
+
+#[test]
+fn test_kraken_applies_recorded_book_and_verifies_checksum() {
+    let recorded = include_str!("../tests/fixtures/kraken_book.jsonl");
+    let mut kraken = Kraken::new(String::from("btcusd"), &Config::default());
+
+    let changes: Vec<Result<bool, OrderbookError>> = recorded.lines()
+        .map(|msg| kraken.apply_message(msg))
+        .collect();
+    assert!(matches!(changes[..6], [Ok(false), Ok(false), Ok(false), Ok(true), Ok(false), Ok(true)]));
+    assert_eq!(kraken.precision, Some((1, 8)));
+    assert!(matches!(changes[6], Err(OrderbookError::ChecksumError)));
+
+    let summary = kraken.book.summarise(EXCHANGE_NAME, 10);
+    assert_eq!(summary.bids[0].price, 45283.4);
+    assert_eq!(summary.asks[0].price, 45285.0);
+
+    // Updates wait for the next snapshot.
+    let update = recorded.lines().nth(5).unwrap();
+    assert!(!kraken.apply_message(update).unwrap());
+
+    let error = r#"{"error":"Currency pair not supported BTC/XYZ","method":"subscribe","success":false,"symbol":"BTC/XYZ"}"#;
+    assert!(matches!(kraken.apply_message(error), Err(OrderbookError::SubscriptionError)));
+
+    // The book is not verified, nor reported, before the precisions.
+    let mut kraken = Kraken::new(String::from("btcusd"), &Config::default());
+    let snapshot = recorded.lines().nth(3).unwrap();
+    assert!(!kraken.apply_message(snapshot).unwrap());
+
+    let instruments = r#"{"channel":"instrument","type":"snapshot","data":{"assets":[],"pairs":[]}}"#;
+    assert!(matches!(
+        kraken.apply_message(instruments),
+        Err(OrderbookError::UnknownSymbolError(_))));
+}
@@ -0,0 +1,276 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::book::{OrderBook, Side};
+use crate::config::Config;
+use crate::error::OrderbookError;
+use crate::exchange::{Exchange, self, Socket};
+use crate::orderbook_aggregator::Summary;
+
+pub static EXCHANGE_NAME: &str = "OKX";
+static WSS_BASE_ENDPOINT: &str = "wss://ws.okx.com:8443/ws/v5/public";
+
+/// Number of levels per side covered by OKX's book checksum.
+const CHECKSUM_DEPTH: usize = 25;
+
+/// Depth of the `books5` channel, used instead of the full `books` channel
+/// when no more levels are needed.
+const BOOKS5_DEPTH: usize = 5;
+
+/// A level as `[price, size, deprecated, number of orders]`.
+pub type OkxLevel = (String, String, String, String);
+
+/// Any message sent by OKX. Pushes of the book carry `data`, while replies
+/// to requests carry an `event`.
+#[derive(Deserialize)]
+pub struct OkxMsg {
+    pub event: Option<String>,
+    pub msg: Option<String>,
+    pub action: Option<String>,
+    #[serde(default)]
+    pub data: Vec<OkxBook>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OkxBook {
+    #[serde(default)]
+    pub asks: Vec<OkxLevel>,
+    #[serde(default)]
+    pub bids: Vec<OkxLevel>,
+    pub checksum: Option<i32>,
+    pub seq_id: Option<i64>,
+    pub prev_seq_id: Option<i64>,
+}
+
+/// Keeps a local copy of the OKX book from the `books` channel, or from the
+/// `books5` channel when five levels are enough. Every update must follow on
+/// from the previous one by `prevSeqId` and match the CRC32 checksum OKX
+/// sends with it, and the book is subscribed to again when it does not.
+pub struct Okx {
+    socket: Socket,
+    symbol: String,
+    depth: usize,
+    silence_timeout: Duration,
+    channel: &'static str,
+    book: OrderBook,
+    /// Whether `book` holds a snapshot that the updates apply to.
+    is_synced: bool,
+    /// Number of times the book was found out of sync and synced again.
+    gaps: u64,
+    /// `seqId` of the last snapshot or update applied to `book`.
+    last_seq_id: Option<i64>,
+}
+
+impl Okx {
+    pub fn new(symbol: String, config: &Config) -> Self {
+        let channel = match config.max_depth {
+            depth if depth <= BOOKS5_DEPTH => "books5",
+            _ => "books",
+        };
+
+        Okx {
+            socket: None,
+            symbol,
+            depth: config.max_depth,
+            silence_timeout: config.silence_timeout(),
+            channel,
+            book: OrderBook::default(),
+            is_synced: false,
+            gaps: 0,
+            last_seq_id: None,
+        }
+    }
+
+    /// OKX's name for the instrument, such as `BTC-USDT`.
+    fn inst_id(&self) -> Result<String, OrderbookError> {
+        exchange::split_symbol(&self.symbol)
+            .map(|(base, quote)| format!("{}-{}", base, quote))
+            .ok_or_else(|| OrderbookError::UnknownSymbolError(self.symbol.clone()))
+    }
+
+    fn channel_message(&self, op: &str) -> Result<String, OrderbookError> {
+        Ok(json!({
+            "op": op,
+            "args": [{
+                "channel": self.channel,
+                "instId": self.inst_id()?,
+            }],
+        }).to_string())
+    }
+
+    /// Starts the book over from the snapshot OKX pushes first on a new
+    /// subscription of the channel.
+    async fn resubscribe(&mut self) -> Result<(), OrderbookError> {
+        self.book.clear();
+        self.is_synced = false;
+
+        let unsubscribe_message = self.channel_message("unsubscribe")?;
+        let subscribe_message = self.channel_message("subscribe")?;
+        exchange::resubscribe(
+            &mut self.socket,
+            Some(unsubscribe_message),
+            subscribe_message,
+        ).await
+    }
+
+    /// Applies a snapshot or an update of the book, checking that it follows
+    /// on from the last one and verifying the checksum of the result.
+    /// Returns whether the book changed. `books5` pushes have no action and
+    /// are snapshots.
+    fn apply_book(&mut self, action: Option<&str>, book: OkxBook) -> Result<bool, OrderbookError> {
+        match action {
+            None | Some("snapshot") => {
+                self.book.clear();
+                self.is_synced = true;
+            }
+            _ if !self.is_synced => return Ok(false),
+            _ => {
+                if book.prev_seq_id != self.last_seq_id {
+                    self.is_synced = false;
+                    return Err(OrderbookError::SequenceGapError);
+                }
+            }
+        }
+
+        for (price, size, _, _) in &book.bids {
+            self.book.update(Side::Bid, price, size)?;
+        }
+        for (price, size, _, _) in &book.asks {
+            self.book.update(Side::Ask, price, size)?;
+        }
+        self.last_seq_id = book.seq_id;
+
+        if let Some(expected) = book.checksum {
+            if checksum(&self.book) != expected as u32 {
+                self.is_synced = false;
+                return Err(OrderbookError::ChecksumError);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Applies the OKX message `msg`, whose `data` may hold several pushes
+    /// of the book. Returns whether the book changed, and fails when an
+    /// update does not follow on from the last one or the book no longer
+    /// matches OKX's.
+    fn apply_message(&mut self, msg: &str) -> Result<bool, OrderbookError> {
+        let okx_msg: OkxMsg = match serde_json::from_str(msg) {
+            Ok(okx_msg) => okx_msg,
+            Err(_) => {
+                return Err(OrderbookError::JsonParseError);
+            }
+        };
+
+        if okx_msg.event.as_deref() == Some("error") {
+            println!("{} Error: {}", EXCHANGE_NAME, okx_msg.msg.unwrap_or_default());
+            return Err(OrderbookError::SubscriptionError);
+        }
+
+        let mut is_changed = false;
+        for book in okx_msg.data {
+            is_changed |= self.apply_book(okx_msg.action.as_deref(), book)?;
+        }
+
+        Ok(is_changed)
+    }
+}
+
+/// OKX's checksum of `book`: the CRC32 of the price and size of the top 25
+/// bids and asks as sent by OKX, alternating a bid and an ask and separated
+/// by colons.
+fn checksum(book: &OrderBook) -> u32 {
+    let fields: Vec<&str> = book.interleave(CHECKSUM_DEPTH).into_iter()
+        .flat_map(|(_, level)| [level.raw_price.as_str(), level.raw_amount.as_str()])
+        .collect();
+
+    crc32fast::hash(fields.join(":").as_bytes())
+}
+
+#[tonic::async_trait]
+impl Exchange for Okx {
+    async fn connect(&mut self) -> Result<(), OrderbookError> {
+        self.socket = None;
+        self.book.clear();
+        self.is_synced = false;
+        self.last_seq_id = None;
+
+        let subscribe_message = self.channel_message("subscribe")?;
+
+        let mut socket = exchange::connect(WSS_BASE_ENDPOINT).await?;
+        exchange::send_text(&mut socket, subscribe_message).await?;
+        self.socket = Some(socket);
+
+        Ok(())
+    }
+
+    async fn close(&mut self) {
+        if let Ok(unsubscribe_message) = self.channel_message("unsubscribe") {
+            let _ = exchange::send(&mut self.socket, unsubscribe_message).await;
+        }
+
+        exchange::close(&mut self.socket).await;
+    }
+
+    async fn stream(&mut self) -> Result<Option<Summary>, OrderbookError> {
+        loop {
+            let socket = match &mut self.socket {
+                Some(socket) => socket,
+                None => {
+                    return Err(OrderbookError::NoConnectionError);
+                }
+            };
+
+            let msg = exchange::read_text(socket, self.silence_timeout).await?;
+
+            match self.apply_message(&msg) {
+                Ok(true) => {
+                    return Ok(Some(self.book.summarise(EXCHANGE_NAME, self.depth)));
+                }
+                Ok(false) => (),
+                Err(e @ (OrderbookError::SequenceGapError | OrderbookError::ChecksumError)) => {
+                    println!("{} {}: {}, resubscribing", EXCHANGE_NAME, self.symbol, e);
+                    self.gaps += 1;
+                    self.resubscribe().await?;
+                    return Ok(None);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn gaps(&self) -> u64 {
+        self.gaps
+    }
+}
+
+#[test]
+fn test_okx_applies_recorded_books_and_checks_sequence() {
+    let recorded = include_str!("../tests/fixtures/okx_books.jsonl");
+    let mut okx = Okx::new(String::from("btcusdt"), &Config::default());
+    assert_eq!(okx.inst_id().unwrap(), "BTC-USDT");
+
+    let changes: Vec<Result<bool, OrderbookError>> = recorded.lines()
+        .map(|msg| okx.apply_message(msg))
+        .collect();
+    // An update without changes keeps the sequence id, and a push may carry
+    // several updates.
+    assert!(matches!(changes[..5], [Ok(false), Ok(true), Ok(true), Ok(true), Ok(true)]));
+    assert!(matches!(changes[5], Err(OrderbookError::SequenceGapError)));
+    assert_eq!(okx.last_seq_id, Some(123459));
+
+    let summary = okx.book.summarise(EXCHANGE_NAME, 10);
+    assert_eq!(summary.bids.len(), 1);
+    assert_eq!(summary.bids[0].amount, 300.0);
+    assert_eq!(summary.asks[0].price, 8476.99);
+
+    // A snapshot that does not match its checksum.
+    let snapshot = recorded.lines().nth(1).unwrap().replace("\"415\"", "\"416\"");
+    assert!(matches!(okx.apply_message(&snapshot), Err(OrderbookError::ChecksumError)));
+
+    let error = r#"{"event":"error","code":"60018","msg":"Wrong URL or channel:books,instId:BTC-XYZ doesn't exist.","connId":"a4d3ae55"}"#;
+    assert!(matches!(okx.apply_message(error), Err(OrderbookError::SubscriptionError)));
+}
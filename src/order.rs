@@ -1,14 +1,5 @@
 use serde::{de, Deserialize, Deserializer, Serialize};
 
-use crate::orderbook_aggregator::{Level, Summary};
-
-
-pub trait Order {
-    fn exchange_name(&self) -> String;
-    fn asks(&self) -> &Vec<Quote>;
-    fn bids(&self) -> &Vec<Quote>;
-}
-
 #[derive(Clone, Deserialize, Serialize, PartialEq)]
 pub struct Quote {
     #[serde(deserialize_with = "from_str")]
@@ -25,37 +16,6 @@ where
     string_num.parse::<f64>().map_err(de::Error::custom)
 }
 
-pub fn summarise_order<T: Order>(order: T) -> Summary {
-    let mut asks = Vec::new();
-    let mut bids = Vec::new();
-
-    for (ask, bid) in
-        order.asks().iter().zip(order.bids().iter()) {
-        let ask_level = Level {
-            exchange: order.exchange_name(),
-            amount: ask.amount,
-            price: ask.price,
-        };
-
-        asks.push(ask_level);
-
-        let bid_level = Level {
-            exchange: order.exchange_name(),
-            amount: bid.amount,
-            price: bid.price,
-        };
-        bids.push(bid_level);
-    }
-
-    let spread = bids[0].price - asks[0].price;
-    Summary {
-        asks,
-        bids,
-        spread,
-    }
-}
-
-
 #[test]
 fn test_deserialize_from_str_succeeds() {
     use serde_json::json;
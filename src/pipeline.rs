@@ -0,0 +1,417 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use tokio::sync::{broadcast, watch, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+
+use crate::config::Config;
+use crate::error::OrderbookError;
+use crate::orderbook_aggregator::{ConnectionState, Summary, SymbolInfo, VenueStatus};
+use crate::venue::VenueRegistry;
+use crate::worker::symbol_worker;
+
+/// Number of summaries buffered for each subscriber before the slowest ones
+/// start missing updates.
+const BROADCAST_CAPACITY: usize = 16;
+
+pub type PipelineId = u64;
+
+/// Where a pipeline's worker publishes the aggregated summaries of its symbol.
+#[derive(Debug)]
+pub struct PipelineOutput {
+    summaries: broadcast::Sender<Summary>,
+    latest: watch::Sender<Option<Summary>>,
+    venues: std::sync::Mutex<BTreeMap<String, VenueHealth>>,
+}
+
+/// What a pipeline knows of its connection to one exchange.
+#[derive(Debug, Clone, Copy)]
+struct VenueHealth {
+    state: ConnectionState,
+    gaps: u64,
+}
+
+impl PipelineOutput {
+    fn new() -> Self {
+        let (summaries, _) = broadcast::channel(BROADCAST_CAPACITY);
+        let (latest, _) = watch::channel(None);
+
+        PipelineOutput {
+            summaries,
+            latest,
+            venues: std::sync::Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Records the connection state of `exchange` for this pipeline.
+    pub fn set_venue_state(&self, exchange: &str, state: ConnectionState) {
+        self.venue_health(exchange, |health| health.state = state);
+    }
+
+    /// Records how many times the book of `exchange` was synced again after
+    /// a gap.
+    pub fn set_venue_gaps(&self, exchange: &str, gaps: u64) {
+        self.venue_health(exchange, |health| health.gaps = gaps);
+    }
+
+    fn venue_health(&self, exchange: &str, update: impl FnOnce(&mut VenueHealth)) {
+        let mut venues = self.venues.lock().unwrap();
+        let health = venues.entry(String::from(exchange))
+            .or_insert(VenueHealth {
+                state: ConnectionState::Disconnected,
+                gaps: 0,
+            });
+        update(health);
+    }
+
+    fn venue_statuses(&self, symbol: &str) -> Vec<VenueStatus> {
+        self.venues.lock().unwrap().iter()
+            .map(|(exchange, health)| VenueStatus {
+                exchange: exchange.clone(),
+                symbol: String::from(symbol),
+                state: health.state as i32,
+                gaps: health.gaps,
+            })
+            .collect()
+    }
+
+    /// Sends `summary` to every subscriber and keeps it as the latest
+    /// summary of the symbol.
+    pub fn publish(&self, summary: Summary) {
+        self.latest.send_replace(Some(summary.clone()));
+        // Sending only fails while nobody is subscribed, which is expected
+        // during the grace period before the pipeline is stopped.
+        let _ = self.summaries.send(summary);
+    }
+}
+
+/// A running exchange pipeline for a single symbol. The pipeline owns the
+/// exchange connections and fans the aggregated summary out to every
+/// subscriber of the symbol.
+#[derive(Debug)]
+struct Pipeline {
+    id: PipelineId,
+    output: Arc<PipelineOutput>,
+    shutdown: watch::Sender<bool>,
+    worker: JoinHandle<()>,
+    subscribers: usize,
+    /// Bumped every time the last subscriber leaves so that a pending grace
+    /// period timer can tell whether the pipeline was reused in the meantime.
+    idle_epoch: u64,
+}
+
+#[derive(Debug, Default)]
+struct Pipelines {
+    next_id: PipelineId,
+    by_symbol: HashMap<String, Pipeline>,
+    /// Set once the server is shutting down, after which no pipeline starts.
+    is_closed: bool,
+}
+
+/// Starts one pipeline per symbol when its first subscriber arrives and stops
+/// it once the symbol has had no subscribers for the configured grace period.
+#[derive(Debug, Clone)]
+pub struct PipelineManager {
+    config: Arc<Config>,
+    venues: Arc<VenueRegistry>,
+    pipelines: Arc<Mutex<Pipelines>>,
+}
+
+impl PipelineManager {
+    pub fn new(config: Arc<Config>, venues: Arc<VenueRegistry>) -> Self {
+        PipelineManager {
+            config,
+            venues,
+            pipelines: Arc::new(Mutex::new(Pipelines::default())),
+        }
+    }
+
+    /// Subscribes to the aggregated summaries of `symbol`, starting the
+    /// pipeline for the symbol if it is not running yet.
+    pub async fn subscribe(
+        &self,
+        symbol: &str,
+    ) -> Result<broadcast::Receiver<Summary>, OrderbookError> {
+        Ok(self.acquire(symbol).await?.summaries.subscribe())
+    }
+
+    /// Returns the latest aggregated summary of `symbol`. A pipeline is
+    /// started for the symbol if none is running, and is left to stop after
+    /// the grace period unless somebody else subscribes to it.
+    pub async fn snapshot(&self, symbol: &str) -> Result<Summary, OrderbookError> {
+        let mut latest = self.acquire(symbol).await?.latest.subscribe();
+
+        let summary = match tokio::time::timeout(
+            self.config.snapshot_timeout(),
+            latest.wait_for(|summary| summary.is_some()),
+        ).await {
+            Ok(Ok(summary)) => summary.clone(),
+            _ => None,
+        };
+        self.unsubscribe(symbol).await;
+
+        summary.ok_or_else(|| {
+            OrderbookError::SnapshotUnavailableError(String::from(symbol))
+        })
+    }
+
+    /// Describes every running pipeline, sorted by symbol.
+    pub async fn list(&self) -> Vec<SymbolInfo> {
+        let pipelines = self.pipelines.lock().await;
+
+        let mut symbols: Vec<SymbolInfo> = pipelines.by_symbol.iter()
+            .map(|(symbol, pipeline)| SymbolInfo {
+                symbol: symbol.clone(),
+                subscribers: pipeline.subscribers as u32,
+                exchanges: pipeline.output.venue_statuses(symbol),
+            })
+            .collect();
+        symbols.sort_by(|a, b| a.symbol.cmp(&b.symbol));
+
+        symbols
+    }
+
+    /// Adds a subscriber to the pipeline of `symbol`, starting the pipeline
+    /// if it is not running yet, and returns the pipeline's output.
+    async fn acquire(&self, symbol: &str) -> Result<Arc<PipelineOutput>, OrderbookError> {
+        let mut pipelines = self.pipelines.lock().await;
+
+        if pipelines.is_closed {
+            return Err(OrderbookError::ShuttingDownError);
+        }
+
+        if let Some(pipeline) = pipelines.by_symbol.get_mut(symbol) {
+            pipeline.subscribers += 1;
+            return Ok(pipeline.output.clone());
+        }
+
+        pipelines.next_id += 1;
+        let id = pipelines.next_id;
+        let output = Arc::new(PipelineOutput::new());
+        let (shutdown, shutdown_receiver) = watch::channel(false);
+
+        println!("Starting pipeline for {}", symbol);
+
+        let manager = self.clone();
+        let worker_symbol = String::from(symbol);
+        let worker_output = output.clone();
+        let worker = tokio::spawn(async move {
+            symbol_worker(
+                worker_symbol.clone(),
+                manager.config.clone(),
+                &manager.venues,
+                worker_output,
+                shutdown_receiver,
+            ).await;
+            manager.remove(&worker_symbol, id).await;
+        });
+
+        let pipeline = Pipeline {
+            id,
+            output: output.clone(),
+            shutdown,
+            worker,
+            subscribers: 1,
+            idle_epoch: 0,
+        };
+        pipelines.by_symbol.insert(String::from(symbol), pipeline);
+
+        Ok(output)
+    }
+
+    /// Refuses new subscriptions from now on, leaving the running pipelines
+    /// as they are.
+    pub async fn close(&self) {
+        self.pipelines.lock().await.is_closed = true;
+    }
+
+    /// Stops every pipeline and refuses to start new ones. Waits until
+    /// `deadline` for the workers to close their exchange connections, and
+    /// aborts the ones that are still running by then.
+    pub async fn shutdown(&self, deadline: Instant) {
+        let stopped: Vec<(String, Pipeline)> = {
+            let mut pipelines = self.pipelines.lock().await;
+            pipelines.is_closed = true;
+            pipelines.by_symbol.drain().collect()
+        };
+
+        for (_, pipeline) in &stopped {
+            let _ = pipeline.shutdown.send(true);
+        }
+
+        for (symbol, mut pipeline) in stopped {
+            if time::timeout_at(deadline, &mut pipeline.worker).await.is_err() {
+                println!("Pipeline for {} did not stop in time", symbol);
+                pipeline.worker.abort();
+            }
+        }
+    }
+
+    /// Releases a subscription to `symbol`. When the last subscriber leaves,
+    /// the pipeline is stopped unless somebody subscribes again within the
+    /// grace period.
+    pub async fn unsubscribe(&self, symbol: &str) {
+        let mut pipelines = self.pipelines.lock().await;
+
+        let pipeline = match pipelines.by_symbol.get_mut(symbol) {
+            Some(pipeline) => pipeline,
+            None => return,
+        };
+
+        pipeline.subscribers = pipeline.subscribers.saturating_sub(1);
+        if pipeline.subscribers > 0 {
+            return;
+        }
+
+        pipeline.idle_epoch += 1;
+        let id = pipeline.id;
+        let idle_epoch = pipeline.idle_epoch;
+
+        let manager = self.clone();
+        let symbol = String::from(symbol);
+        tokio::spawn(async move {
+            tokio::time::sleep(manager.config.pipeline_grace_period()).await;
+            manager.stop_if_idle(&symbol, id, idle_epoch).await;
+        });
+    }
+
+    async fn stop_if_idle(&self, symbol: &str, id: PipelineId, idle_epoch: u64) {
+        let mut pipelines = self.pipelines.lock().await;
+
+        let is_idle = match pipelines.by_symbol.get(symbol) {
+            Some(pipeline) => {
+                pipeline.id == id &&
+                pipeline.idle_epoch == idle_epoch &&
+                pipeline.subscribers == 0
+            }
+            None => false,
+        };
+
+        if is_idle {
+            if let Some(pipeline) = pipelines.by_symbol.remove(symbol) {
+                println!("Stopping idle pipeline for {}", symbol);
+                let _ = pipeline.shutdown.send(true);
+            }
+        }
+    }
+
+    /// Forgets the pipeline `id` for `symbol` once its worker has exited, so
+    /// that its subscribers see the end of the stream and the next subscriber
+    /// starts a fresh pipeline.
+    async fn remove(&self, symbol: &str, id: PipelineId) {
+        let mut pipelines = self.pipelines.lock().await;
+
+        let is_same_pipeline = pipelines.by_symbol.get(symbol)
+            .map(|pipeline| pipeline.id == id)
+            .unwrap_or(false);
+
+        if is_same_pipeline {
+            pipelines.by_symbol.remove(symbol);
+        }
+    }
+}
+
+/// Builds a manager whose pipelines read a single venue that never finishes
+/// connecting, so that they run until they are stopped. The venue is served
+/// by the returned listener, which must outlive the pipelines.
+#[cfg(test)]
+fn test_manager() -> (PipelineManager, std::net::TcpListener) {
+    use serde_json::json;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let venue = serde_json::from_value(json!({
+        "name": "Stub",
+        "endpoint": format!("ws://{}/", listener.local_addr().unwrap()),
+        "bids": "/bids",
+        "asks": "/asks",
+    })).unwrap();
+
+    let config = Arc::new(Config {
+        exchanges: Vec::new(),
+        venues: vec![venue],
+        pipeline_grace_period_ms: 5000,
+        ..Config::default()
+    });
+    let venues = Arc::new(VenueRegistry::from_config(&config).unwrap());
+
+    (PipelineManager::new(config, venues), listener)
+}
+
+#[cfg(test)]
+async fn subscribers(manager: &PipelineManager, symbol: &str) -> Option<usize> {
+    manager.pipelines.lock().await.by_symbol.get(symbol)
+        .map(|pipeline| pipeline.subscribers)
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_pipeline_is_shared_and_stops_after_grace_period() {
+    use std::time::Duration;
+
+    let (manager, _venue) = test_manager();
+    let _first = manager.subscribe("btcusdt").await.unwrap();
+    let _second = manager.subscribe("btcusdt").await.unwrap();
+    assert_eq!(subscribers(&manager, "btcusdt").await, Some(2));
+    assert_eq!(manager.list().await.len(), 1);
+
+    manager.unsubscribe("btcusdt").await;
+    manager.unsubscribe("btcusdt").await;
+    assert_eq!(subscribers(&manager, "btcusdt").await, Some(0));
+
+    time::sleep(Duration::from_millis(4900)).await;
+    assert_eq!(subscribers(&manager, "btcusdt").await, Some(0));
+
+    time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(subscribers(&manager, "btcusdt").await, None);
+    assert!(manager.list().await.is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_pipeline_stop_is_cancelled_by_resubscribing() {
+    use std::time::Duration;
+
+    let (manager, _venue) = test_manager();
+    let _first = manager.subscribe("btcusdt").await.unwrap();
+    manager.unsubscribe("btcusdt").await;
+
+    // Subscribing within the grace period keeps the pipeline, and the timer
+    // of the earlier idle period no longer applies once the subscriber
+    // leaves again.
+    time::sleep(Duration::from_millis(3000)).await;
+    let _second = manager.subscribe("btcusdt").await.unwrap();
+    manager.unsubscribe("btcusdt").await;
+
+    time::sleep(Duration::from_millis(2100)).await;
+    assert_eq!(subscribers(&manager, "btcusdt").await, Some(0));
+
+    time::sleep(Duration::from_millis(3000)).await;
+    assert_eq!(subscribers(&manager, "btcusdt").await, None);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_pipeline_timer_ignores_later_pipeline_of_symbol() {
+    use std::time::Duration;
+
+    let (manager, _venue) = test_manager();
+    let _first = manager.subscribe("btcusdt").await.unwrap();
+    let first_id = manager.pipelines.lock().await.by_symbol["btcusdt"].id;
+    manager.unsubscribe("btcusdt").await;
+
+    // The first pipeline is stopped early, and a new pipeline of the symbol
+    // goes idle at the same epoch before the first timer fires.
+    time::sleep(Duration::from_millis(1000)).await;
+    manager.stop_if_idle("btcusdt", first_id, 1).await;
+    assert_eq!(subscribers(&manager, "btcusdt").await, None);
+
+    let _second = manager.subscribe("btcusdt").await.unwrap();
+    let second_id = manager.pipelines.lock().await.by_symbol["btcusdt"].id;
+    assert_ne!(first_id, second_id);
+    time::sleep(Duration::from_millis(1000)).await;
+    manager.unsubscribe("btcusdt").await;
+
+    time::sleep(Duration::from_millis(3100)).await;
+    assert_eq!(subscribers(&manager, "btcusdt").await, Some(0));
+
+    time::sleep(Duration::from_millis(2000)).await;
+    assert_eq!(subscribers(&manager, "btcusdt").await, None);
+}
@@ -1,69 +1,518 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
-use crate::client::Client;
+use crate::client::{Client, ClientRegistry, SubscriptionOptions};
+use crate::config::Config;
+use crate::delta::DeltaEncoder;
+use crate::error::OrderbookError;
+use crate::pipeline::PipelineManager;
+use crate::venue::VenueRegistry;
 use crate::worker::client_worker;
-use orderbook_aggregator::{Summary, orderbook_aggregator_server::{OrderbookAggregator, OrderbookAggregatorServer}, Symbol};
-use tokio::sync::{mpsc, Mutex};
+use orderbook_aggregator::{
+    BookDelta,
+    ClientList,
+    DeltaRequest,
+    ExchangeInfo,
+    ExchangeList,
+    ListClientsRequest,
+    ListExchangesRequest,
+    ListSymbolsRequest,
+    Summary,
+    orderbook_aggregator_server::{OrderbookAggregator, OrderbookAggregatorServer},
+    Symbol,
+    SymbolList,
+    Symbols,
+    SubscriptionCommand,
+    subscription_command::Command,
+};
+use futures_util::future::join_all;
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio::time::{self, Instant};
 use tokio_stream::wrappers::{ReceiverStream,};
-use tonic::{Request, Response, Status, transport::Server};
+use tonic::{Request, Response, Status, Streaming, transport::Server};
 
 pub mod orderbook_aggregator {
     tonic::include_proto!("orderbook");
 }
 
 mod aggregator;
+mod backoff;
 mod binance;
+mod bitfinex;
 mod bitstamp;
+mod book;
+mod bybit;
 mod client;
+mod coinbase;
+mod config;
+mod delta;
 mod error;
 mod exchange;
+mod generic;
+mod kraken;
+mod okx;
 mod order;
+mod pipeline;
+mod venue;
 mod worker;
 
-#[derive(Debug)]
+/// Number of summaries buffered for a subscription session, shared by all the
+/// symbols of the session.
+const SESSION_CHANNEL_CAPACITY: usize = 16;
+
+#[derive(Debug, Clone)]
 struct OrderbookAggregatorService {
-    client: Arc<Mutex<Client>>,
+    config: Arc<Config>,
+    venues: Arc<VenueRegistry>,
+    clients: Arc<Mutex<ClientRegistry>>,
+    pipelines: PipelineManager,
+    /// Set on shutdown, so that streaming sessions send their final status
+    /// and end even when they have no subscription to be stopped through.
+    stop_sessions: Arc<watch::Sender<bool>>,
+}
+
+impl OrderbookAggregatorService {
+    /// Registers a client for `symbol` and starts forwarding the symbol's
+    /// summaries, narrowed down by `options`, to `sender`.
+    async fn subscribe_client(
+        &self,
+        symbol: String,
+        options: SubscriptionOptions,
+        sender: mpsc::Sender<Result<Summary, Status>>,
+    ) -> Result<Client, OrderbookError> {
+        // The registry stays locked while subscribing, so that a shutdown
+        // either sees the client or refuses its subscription.
+        let mut clients = self.clients.lock().await;
+        let summaries = self.pipelines.subscribe(&symbol).await?;
+        let client = clients.register(symbol, options, sender);
+        drop(clients);
+        println!("Client {} subscribed to {}", client.id, client.symbol);
+
+        let clients = self.clients.clone();
+        let pipelines = self.pipelines.clone();
+        let worker_client = client.clone();
+        tokio::spawn(async move {
+            client_worker(worker_client, summaries, clients, pipelines).await;
+        });
+
+        Ok(client)
+    }
+
+    /// Resolves once the server is shutting down, straight away if it
+    /// already is.
+    async fn sessions_stopping(&self) {
+        let _ = self.stop_sessions.subscribe().wait_for(|stopping| *stopping).await;
+    }
+
+    /// Applies the subscription commands received on `commands` until the
+    /// client goes away or the server shuts down, streaming the summaries of
+    /// the subscribed symbols to `sender`. An invalid command ends the
+    /// session with an error.
+    async fn run_session(
+        &self,
+        mut commands: Streaming<SubscriptionCommand>,
+        sender: mpsc::Sender<Result<Summary, Status>>,
+    ) {
+        let mut subscriptions: HashMap<String, Client> = HashMap::new();
+        let mut has_commands = true;
+
+        loop {
+            let command = tokio::select! {
+                _ = sender.closed() => break,
+                _ = self.sessions_stopping() => {
+                    let _ = sender.send(Err(OrderbookError::ShuttingDownError.into())).await;
+                    break;
+                }
+                command = commands.message(), if has_commands => command,
+            };
+
+            let command = match command {
+                Ok(Some(SubscriptionCommand { command: Some(command) })) => command,
+                Ok(Some(_)) => continue,
+                Ok(None) => {
+                    // The client has no more commands but keeps receiving
+                    // its subscriptions until it disconnects.
+                    has_commands = false;
+                    continue;
+                }
+                Err(e) => {
+                    println!("Subscription session failed: {}", e);
+                    break;
+                }
+            };
+
+            match command {
+                Command::Subscribe(request) => {
+                    let options = match SubscriptionOptions::from_request(
+                        &request,
+                        &self.venues,
+                        self.config.max_depth,
+                    ) {
+                        Ok(options) => options,
+                        Err(e) => {
+                            let _ = sender.send(Err(e.into())).await;
+                            break;
+                        }
+                    };
+
+                    if let Some(client) = subscriptions.remove(&request.symbol) {
+                        client.stop.notify_one();
+                    }
+
+                    match self.subscribe_client(
+                        request.symbol.clone(),
+                        options,
+                        sender.clone(),
+                    ).await {
+                        Ok(client) => {
+                            subscriptions.insert(request.symbol, client);
+                        }
+                        Err(e) => {
+                            let _ = sender.send(Err(e.into())).await;
+                            break;
+                        }
+                    }
+                }
+                Command::Unsubscribe(symbol) => {
+                    if let Some(client) = subscriptions.remove(&symbol) {
+                        client.stop.notify_one();
+                    }
+                }
+            }
+        }
+
+        for client in subscriptions.values() {
+            client.stop.notify_one();
+        }
+    }
+
+    /// Streams the deltas of the symbol named in the first of `requests` to
+    /// `sender` until the client goes away or the server shuts down. Later
+    /// requests may ask for a full snapshot.
+    async fn run_delta_session(
+        &self,
+        mut requests: Streaming<DeltaRequest>,
+        sender: mpsc::Sender<Result<BookDelta, Status>>,
+    ) {
+        let first_request = tokio::select! {
+            _ = self.sessions_stopping() => {
+                let _ = sender.send(Err(OrderbookError::ShuttingDownError.into())).await;
+                return;
+            }
+            request = requests.message() => match request {
+                Ok(Some(request)) => request,
+                _ => return,
+            },
+        };
+
+        let symbol_request = match first_request.symbol {
+            Some(symbol_request) => symbol_request,
+            None => {
+                let _ = sender.send(Err(Status::invalid_argument(
+                    "The first delta request must name a symbol"
+                ))).await;
+                return;
+            }
+        };
+
+        let options = match SubscriptionOptions::from_request(
+            &symbol_request,
+            &self.venues,
+            self.config.max_depth,
+        ) {
+            Ok(options) => options,
+            Err(e) => {
+                let _ = sender.send(Err(e.into())).await;
+                return;
+            }
+        };
+
+        let mut encoder = DeltaEncoder::new(first_request.snapshot_interval);
+        let (summary_sender, mut summaries) = mpsc::channel(1);
+        let client = match self.subscribe_client(
+            symbol_request.symbol,
+            options,
+            summary_sender,
+        ).await {
+            Ok(client) => client,
+            Err(e) => {
+                let _ = sender.send(Err(e.into())).await;
+                return;
+            }
+        };
+
+        let mut has_requests = true;
+        loop {
+            tokio::select! {
+                _ = sender.closed() => break,
+                _ = self.sessions_stopping() => {
+                    let _ = sender.send(Err(OrderbookError::ShuttingDownError.into())).await;
+                    break;
+                }
+                request = requests.message(), if has_requests => match request {
+                    Ok(Some(request)) => {
+                        if request.snapshot {
+                            encoder.request_snapshot();
+                        }
+                    }
+                    Ok(None) => has_requests = false,
+                    Err(_) => break,
+                },
+                summary = summaries.recv() => match summary {
+                    Some(Ok(summary)) => {
+                        if sender.send(Ok(encoder.encode(&summary))).await.is_err() {
+                            break;
+                        }
+                    }
+                    Some(Err(status)) => {
+                        let _ = sender.send(Err(status)).await;
+                        break;
+                    }
+                    None => break,
+                },
+            }
+        }
+
+        client.stop.notify_one();
+    }
+
+    /// Refuses new subscriptions, sends every client and streaming session a
+    /// final status and stops the pipelines, giving everything until the
+    /// configured shutdown timeout to wind down.
+    async fn shutdown(&self) {
+        let deadline = Instant::now() + self.config.shutdown_timeout();
+
+        self.pipelines.close().await;
+        let _ = self.stop_sessions.send(true);
+        let clients = self.clients.lock().await.clients();
+        let pipelines = self.pipelines.clone();
+        let stopping_pipelines = tokio::spawn(async move {
+            pipelines.shutdown(deadline).await;
+        });
+
+        // Every client is sent its status at once, so that a client that
+        // does not read cannot hold up the others.
+        join_all(clients.iter().map(|client| async move {
+            let status = OrderbookError::ShuttingDownError.into();
+            let _ = time::timeout_at(deadline, client.sender.send(Err(status))).await;
+            client.stop.notify_one();
+        })).await;
+
+        let _ = stopping_pipelines.await;
+    }
+}
+
+/// Resolves once the process is asked to stop with SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => (),
+                    _ = terminate.recv() => (),
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
 }
 
 #[tonic::async_trait]
 impl OrderbookAggregator for OrderbookAggregatorService {
     type BookSummaryStream = ReceiverStream<Result<Summary, Status>>;
+    type BookSummariesStream = ReceiverStream<Result<Summary, Status>>;
+    type SubscribeStream = ReceiverStream<Result<Summary, Status>>;
+    type BookDeltasStream = ReceiverStream<Result<BookDelta, Status>>;
 
     async fn book_summary(
         &self,
         request: Request<Symbol>,
     ) -> Result<Response<Self::BookSummaryStream>, Status> {
+        let options = SubscriptionOptions::from_request(
+            request.get_ref(),
+            &self.venues,
+            self.config.max_depth,
+        )?;
         let (sender, receiver) = mpsc::channel(1);
 
-        let mut locked_client = self.client.lock().await;
-        locked_client.sender = Some(sender);
-        locked_client.symbol = request.get_ref().symbol.clone();
+        self.subscribe_client(
+            request.into_inner().symbol,
+            options,
+            sender,
+        ).await?;
+
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn book_summaries(
+        &self,
+        request: Request<Symbols>,
+    ) -> Result<Response<Self::BookSummariesStream>, Status> {
+        let requests = request.into_inner().symbols;
+        if requests.is_empty() {
+            return Err(Status::invalid_argument("No symbols requested"));
+        }
+
+        // Validate every symbol before subscribing to any of them so that a
+        // bad request does not leave subscriptions behind.
+        let mut subscriptions = Vec::new();
+        for request in requests {
+            if subscriptions.iter().any(|(symbol, _)| *symbol == request.symbol) {
+                return Err(Status::invalid_argument(
+                    format!("Symbol {} requested more than once", request.symbol)
+                ));
+            }
+
+            let options = SubscriptionOptions::from_request(
+                &request,
+                &self.venues,
+                self.config.max_depth,
+            )?;
+            subscriptions.push((request.symbol, options));
+        }
+
+        let (sender, receiver) = mpsc::channel(subscriptions.len());
+        for (symbol, options) in subscriptions {
+            self.subscribe_client(symbol, options, sender.clone()).await?;
+        }
+
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn subscribe(
+        &self,
+        request: Request<Streaming<SubscriptionCommand>>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        let commands = request.into_inner();
+        let (sender, receiver) = mpsc::channel(SESSION_CHANNEL_CAPACITY);
+
+        let service = self.clone();
+        tokio::spawn(async move {
+            service.run_session(commands, sender).await;
+        });
+
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
 
-        let client = self.client.clone();
+    async fn book_deltas(
+        &self,
+        request: Request<Streaming<DeltaRequest>>,
+    ) -> Result<Response<Self::BookDeltasStream>, Status> {
+        let requests = request.into_inner();
+        let (sender, receiver) = mpsc::channel(1);
+
+        let service = self.clone();
         tokio::spawn(async move {
-            client_worker(client).await;
+            service.run_delta_session(requests, sender).await;
         });
 
         Ok(Response::new(ReceiverStream::new(receiver)))
     }
+
+    async fn get_book_snapshot(
+        &self,
+        request: Request<Symbol>,
+    ) -> Result<Response<Summary>, Status> {
+        let options = SubscriptionOptions::from_request(
+            request.get_ref(),
+            &self.venues,
+            self.config.max_depth,
+        )?;
+
+        let summary = self.pipelines.snapshot(&request.get_ref().symbol).await?;
+
+        Ok(Response::new(options.apply(&summary)))
+    }
+
+    async fn list_exchanges(
+        &self,
+        _request: Request<ListExchangesRequest>,
+    ) -> Result<Response<ExchangeList>, Status> {
+        let symbols = self.pipelines.list().await;
+
+        let exchanges = self.venues.names()
+            .map(|name| ExchangeInfo {
+                name: String::from(name),
+                symbols: symbols.iter()
+                    .flat_map(|symbol| symbol.exchanges.iter())
+                    .filter(|venue| venue.exchange == *name)
+                    .cloned()
+                    .collect(),
+            })
+            .collect();
+
+        Ok(Response::new(ExchangeList { exchanges }))
+    }
+
+    async fn list_clients(
+        &self,
+        _request: Request<ListClientsRequest>,
+    ) -> Result<Response<ClientList>, Status> {
+        let clients = self.clients.lock().await.list();
+
+        Ok(Response::new(ClientList { clients }))
+    }
+
+    async fn list_symbols(
+        &self,
+        _request: Request<ListSymbolsRequest>,
+    ) -> Result<Response<SymbolList>, Status> {
+        let symbols = self.pipelines.list().await;
+
+        Ok(Response::new(SymbolList { symbols }))
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let addr = "127.0.0.1:8888".parse().unwrap();
+    let config = Arc::new(Config::load()?);
+    let venues = Arc::new(VenueRegistry::from_config(&config)?);
+
+    let addr = config.address.parse()?;
+    let shutdown_timeout = config.shutdown_timeout();
     let orderbook_aggregator = OrderbookAggregatorService {
-        client: Arc::new(Mutex::new(Client {
-            sender: None,
-            symbol: String::from(""),
-        })),
+        config: config.clone(),
+        venues: venues.clone(),
+        clients: Arc::new(Mutex::new(ClientRegistry::default())),
+        pipelines: PipelineManager::new(config, venues),
+        stop_sessions: Arc::new(watch::channel(false).0),
+    };
+
+    let shutdown_service = orderbook_aggregator.clone();
+    let shutdown = async move {
+        shutdown_signal().await;
+        println!("Shutting down");
+        shutdown_service.shutdown().await;
+    };
+
+    // Connections still open once the shutdown timeout has passed, such as
+    // clients that never read their final status, are not waited for.
+    let stopping_service = orderbook_aggregator.clone();
+    let shutdown_deadline = async move {
+        stopping_service.sessions_stopping().await;
+        time::sleep(shutdown_timeout).await;
     };
 
     let orderbook_aggregator_service = OrderbookAggregatorServer::new(orderbook_aggregator);
 
-    Server::builder()
+    let server = Server::builder()
         .add_service(orderbook_aggregator_service)
-        .serve(addr)
-        .await?;
+        .serve_with_shutdown(addr, shutdown);
+
+    tokio::select! {
+        served = server => served?,
+        _ = shutdown_deadline => println!("Shutdown timeout passed, closing remaining connections"),
+    }
 
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,158 @@
+use std::sync::Arc;
+
+use crate::binance::{self, Binance};
+use crate::bitfinex::{self, Bitfinex};
+use crate::bitstamp::{self, Bitstamp};
+use crate::bybit::{self, Bybit};
+use crate::coinbase::{self, Coinbase};
+use crate::config::Config;
+use crate::error::OrderbookError;
+use crate::exchange::Exchange;
+use crate::generic::{Generic, GenericVenueConfig};
+use crate::kraken::{self, Kraken};
+use crate::okx::{self, Okx};
+
+/// Builds the connector of an exchange for a symbol. Building a connector
+/// does not connect it.
+pub type Connector = fn(symbol: String, config: &Config) -> Box<dyn Exchange>;
+
+/// Every exchange the server knows how to connect to, by name.
+pub static CONNECTORS: [(&str, Connector); 7] = [
+    (binance::EXCHANGE_NAME, |symbol, config| Box::new(Binance::new(symbol, config))),
+    (bitstamp::EXCHANGE_NAME, |symbol, config| Box::new(Bitstamp::new(symbol, config))),
+    (kraken::EXCHANGE_NAME, |symbol, config| Box::new(Kraken::new(symbol, config))),
+    (coinbase::EXCHANGE_NAME, |symbol, config| Box::new(Coinbase::new(symbol, config))),
+    (okx::EXCHANGE_NAME, |symbol, config| Box::new(Okx::new(symbol, config))),
+    (bybit::EXCHANGE_NAME, |symbol, config| Box::new(Bybit::new(symbol, config))),
+    (bitfinex::EXCHANGE_NAME, |symbol, config| Box::new(Bitfinex::new(symbol, config))),
+];
+
+/// An exchange the server aggregates.
+#[derive(Debug, Clone)]
+enum Venue {
+    /// An exchange with a connector of its own.
+    BuiltIn(&'static str, Connector),
+    /// A venue described entirely in the configuration.
+    Generic(Arc<GenericVenueConfig>),
+}
+
+impl Venue {
+    fn name(&self) -> &str {
+        match self {
+            Venue::BuiltIn(name, _) => name,
+            Venue::Generic(venue) => &venue.name,
+        }
+    }
+
+    fn connector(&self, symbol: &str, config: &Config) -> Box<dyn Exchange> {
+        match self {
+            Venue::BuiltIn(_, connector) => connector(String::from(symbol), config),
+            Venue::Generic(venue) => {
+                Box::new(Generic::new(String::from(symbol), venue.clone(), config))
+            }
+        }
+    }
+}
+
+/// The exchanges aggregated by the server, as selected in the configuration.
+#[derive(Debug, Clone)]
+pub struct VenueRegistry {
+    venues: Vec<Venue>,
+}
+
+impl VenueRegistry {
+    /// Looks up every exchange listed in `config` and adds the generic
+    /// venues it defines. Fails if the configuration names an exchange the
+    /// server does not know, or defines a venue under a name already taken.
+    pub fn from_config(config: &Config) -> Result<Self, OrderbookError> {
+        let mut registry = VenueRegistry { venues: Vec::new() };
+
+        for name in &config.exchanges {
+            let (known, connector) = CONNECTORS.iter()
+                .find(|(known, _)| known.eq_ignore_ascii_case(name))
+                .ok_or_else(|| OrderbookError::UnknownExchangeError(name.clone()))?;
+
+            if registry.find(known).is_none() {
+                registry.venues.push(Venue::BuiltIn(known, *connector));
+            }
+        }
+
+        for venue in &config.venues {
+            let is_taken = registry.find(&venue.name).is_some() ||
+                CONNECTORS.iter().any(|(known, _)| known.eq_ignore_ascii_case(&venue.name));
+            if is_taken {
+                return Err(OrderbookError::DuplicateExchangeError(venue.name.clone()));
+            }
+
+            registry.venues.push(Venue::Generic(Arc::new(venue.clone())));
+        }
+
+        Ok(registry)
+    }
+
+    /// Names of the registered exchanges, in configuration order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.venues.iter().map(Venue::name)
+    }
+
+    /// The registered exchange called `name`, ignoring case.
+    pub fn find(&self, name: &str) -> Option<&str> {
+        self.names().find(|known| known.eq_ignore_ascii_case(name))
+    }
+
+    /// Builds a connector for `symbol` on every registered exchange.
+    pub fn connectors(
+        &self,
+        symbol: &str,
+        config: &Config,
+    ) -> Vec<(String, Box<dyn Exchange>)> {
+        self.venues.iter()
+            .map(|venue| (String::from(venue.name()), venue.connector(symbol, config)))
+            .collect()
+    }
+}
+
+#[test]
+fn test_registry_selects_configured_exchanges() {
+    let config = Config {
+        exchanges: vec![String::from("bitstamp")],
+        ..Config::default()
+    };
+    let venues = VenueRegistry::from_config(&config).unwrap();
+
+    assert_eq!(venues.names().collect::<Vec<_>>(), vec!["Bitstamp"]);
+    assert_eq!(venues.find("BITSTAMP"), Some("Bitstamp"));
+    assert_eq!(venues.find("Binance"), None);
+    assert_eq!(venues.connectors("btcusdt", &config).len(), 1);
+
+    let config = Config {
+        exchanges: vec![String::from("Mtgox")],
+        ..Config::default()
+    };
+    assert!(VenueRegistry::from_config(&config).is_err());
+
+    let venue = |name: &str| GenericVenueConfig {
+        name: String::from(name),
+        endpoint: String::from("wss://example.com/{symbol}"),
+        subscribe: None,
+        bids: String::from("/bids"),
+        asks: String::from("/asks"),
+        price: crate::generic::LevelField::Index(0),
+        amount: crate::generic::LevelField::Index(1),
+        values: crate::generic::ValueFormat::String,
+        mode: crate::generic::BookMode::Snapshot,
+    };
+    let config = Config {
+        exchanges: vec![String::from("Bitstamp")],
+        venues: vec![venue("Example")],
+        ..Config::default()
+    };
+    let venues = VenueRegistry::from_config(&config).unwrap();
+    assert_eq!(venues.names().collect::<Vec<_>>(), vec!["Bitstamp", "Example"]);
+
+    let config = Config {
+        venues: vec![venue("binance")],
+        ..config
+    };
+    assert!(VenueRegistry::from_config(&config).is_err());
+}
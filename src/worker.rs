@@ -1,103 +1,324 @@
-use std::f64::NAN;
+use std::collections::{BTreeMap, VecDeque};
 use std::sync::Arc;
 
-use tokio::sync::Mutex;
+use tokio::sync::{broadcast, mpsc, watch, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
 
 use crate::{
-    Client,
-    binance::Binance,
-    bitstamp::Bitstamp,
+    client::{Client, ClientRegistry},
+    config::Config,
+    error::OrderbookError,
     exchange::Exchange,
-    orderbook_aggregator::Summary
+    orderbook_aggregator::{ConnectionState, Summary},
+    pipeline::{PipelineManager, PipelineOutput},
+    venue::VenueRegistry,
 };
 
 use crate::aggregator::{Aggregator, QuoteType};
 
-pub async fn client_worker(client: Arc<Mutex<Client>>) {
-    let connected_client = client.clone();
+/// Number of venue updates buffered for the aggregation of a symbol.
+const VENUE_CHANNEL_CAPACITY: usize = 64;
 
-    let mut binance_exchange = Binance::new(
-        connected_client.lock().await.symbol.clone()
-    );
-    let mut bitstamp_exchange = Bitstamp::new(
-        connected_client.lock().await.symbol.clone()
-    );
+/// Index of a venue task among the venues of a pipeline.
+type VenueId = usize;
 
-    let num_top_orders = 10;
+/// What a venue task reports to the pipeline of its symbol.
+enum VenueEvent {
+    /// The orderbook of the exchange changed.
+    Update(VenueId, Summary),
+    /// The connection to the exchange was lost, so its book is stale until
+    /// the exchange is reconnected.
+    Disconnected(VenueId),
+}
 
-    let mut last_bitstamp_summary: Option<Summary> = None;
-    let mut last_binance_summary: Option<Summary> = None;
+/// Reads every exchange of `venues` for `symbol` concurrently and publishes
+/// the aggregated orderbook to `output` as soon as any exchange's book
+/// changes, until `shutdown` is set. Exchanges that fail are reconnected in
+/// the background and left out of the aggregate until they are back.
+pub async fn symbol_worker(
+    symbol: String,
+    config: Arc<Config>,
+    venues: &VenueRegistry,
+    output: Arc<PipelineOutput>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let (events, mut venue_events) = mpsc::channel(VENUE_CHANNEL_CAPACITY);
+    let (stop_venues, venues_shutdown) = watch::channel(false);
 
-    loop {
-        let mut bitstamp_summary = match bitstamp_exchange.stream() {
-            Ok(summary) => summary,
-            Err(e) => {
-                println!("Bitstamp Error: {}", e);
-                break;
-            }
+    let venues: Vec<JoinHandle<()>> = venues.connectors(&symbol, &config).into_iter()
+        .enumerate()
+        .map(|(venue_id, (exchange_name, exchange))| spawn_venue(
+            venue_id,
+            exchange_name,
+            exchange,
+            config.clone(),
+            output.clone(),
+            events.clone(),
+            venues_shutdown.clone(),
+        ))
+        .collect();
+    drop(events);
+
+    let mut summaries: BTreeMap<VenueId, Summary> = BTreeMap::new();
+
+    while !*shutdown.borrow() {
+        let event = tokio::select! {
+            _ = shutdown.changed() => break,
+            event = venue_events.recv() => event,
         };
-        // If there is no new summary from exchange, use the last summary.
-        if bitstamp_summary.is_none() {
-            bitstamp_summary = last_bitstamp_summary.clone();
-        }
-        else {
-            last_bitstamp_summary = bitstamp_summary.clone();
-        }
 
-        let mut binance_summary = match binance_exchange.stream() {
-            Ok(summary) => summary,
-            Err(e) => {
-                println!("Binance Error: {}", e);
-                break;
+        match event {
+            Some(VenueEvent::Update(venue_id, summary)) => {
+                summaries.insert(venue_id, summary);
             }
-        };
-        // If there is no new summary from exchange, use the last summary.
-        if binance_summary.is_none() {
-            binance_summary = last_binance_summary.clone();
-        }
-        else {
-            last_binance_summary = binance_summary.clone();
+            Some(VenueEvent::Disconnected(venue_id)) => {
+                summaries.remove(&venue_id);
+            }
+            None => break,
         }
 
-        let aggregate_asks = Aggregator::aggregate_top(
-            num_top_orders,
-            bitstamp_summary.clone(),
-            binance_summary.clone(),
+        // Every exchange keeps up to `max_depth` levels in the aggregate so
+        // that subscribers can narrow it down to the depth and exchanges
+        // they asked for.
+        let latest: Vec<&Summary> = summaries.values().collect();
+        let aggregate_asks = Aggregator::aggregate(
+            config.max_depth,
+            &latest,
             QuoteType::ASKS,
         );
-        let aggregate_bids = Aggregator::aggregate_top(
-            num_top_orders,
-            bitstamp_summary,
-            binance_summary,
+        let aggregate_bids = Aggregator::aggregate(
+            config.max_depth,
+            &latest,
             QuoteType::BIDS,
         );
 
-        let mut spread = NAN;
-        if aggregate_asks.len() > 0 && aggregate_bids.len() > 0 {
-            spread = aggregate_bids[0].price - aggregate_asks[0].price;
-        }
-
         let aggregate_summary = Summary {
+            spread: Aggregator::spread(&aggregate_bids, &aggregate_asks),
             bids: aggregate_bids,
             asks: aggregate_asks,
-            spread,
+            symbol: symbol.clone(),
         };
 
-        match connected_client.lock().await.sender.as_ref() {
-            Some(sender) => {
-                match sender.send(Ok(aggregate_summary)).await {
-                    Ok(_) => (),
-                    Err(e) => {
-                        println!("Failed to send data to client: {}", e);
+        output.publish(aggregate_summary);
+    }
+
+    let _ = stop_venues.send(true);
+    for venue in venues {
+        let _ = venue.await;
+    }
+
+    println!("Pipeline for {} stopped", symbol);
+}
+
+/// Spawns a task connecting to `exchange` and reporting every change of its
+/// orderbook on `events` as venue `venue_id`, until `shutdown` is set.
+/// Whenever the connection fails it is re-established, and the exchange
+/// resubscribed, after an exponential backoff. A connection that went silent
+/// is reported as stale until then. The connection is closed when the task
+/// stops.
+fn spawn_venue(
+    venue_id: VenueId,
+    exchange_name: String,
+    mut exchange: Box<dyn Exchange>,
+    config: Arc<Config>,
+    output: Arc<PipelineOutput>,
+    events: mpsc::Sender<VenueEvent>,
+    mut shutdown: watch::Receiver<bool>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut backoff = config.reconnect_backoff();
+        let mut gaps = 0;
+
+        'connection: while !*shutdown.borrow() {
+            let mut state = ConnectionState::Disconnected;
+            output.set_venue_state(&exchange_name, ConnectionState::Connecting);
+            let connected = tokio::select! {
+                _ = shutdown.changed() => break,
+                connected = exchange.connect() => connected,
+            };
+
+            match connected {
+                Ok(()) => {
+                    output.set_venue_state(&exchange_name, ConnectionState::Connected);
+
+                    loop {
+                        let summary = tokio::select! {
+                            _ = shutdown.changed() => break 'connection,
+                            summary = exchange.stream() => summary,
+                        };
+
+                        if exchange.gaps() != gaps {
+                            gaps = exchange.gaps();
+                            output.set_venue_gaps(&exchange_name, gaps);
+                        }
+
+                        match summary {
+                            Ok(Some(summary)) => {
+                                backoff.reset();
+                                let update = VenueEvent::Update(venue_id, summary);
+                                if events.send(update).await.is_err() {
+                                    break 'connection;
+                                }
+                            }
+                            Ok(None) => (),
+                            Err(e) => {
+                                println!("{} Error: {}", exchange_name, e);
+                                if let OrderbookError::StaleConnectionError = e {
+                                    state = ConnectionState::Stale;
+                                }
+                                break;
+                            }
+                        }
+                    }
+
+                    exchange.close().await;
+                    let disconnected = VenueEvent::Disconnected(venue_id);
+                    if events.send(disconnected).await.is_err() {
                         break;
                     }
                 }
-            },
-            _ => {
-                println!("Client connection does not exists");
-                break;
+                Err(e) => {
+                    println!("{} Error: {}", exchange_name, e);
+                }
             }
-        };
+
+            output.set_venue_state(&exchange_name, state);
+
+            let delay = backoff.next_delay();
+            println!("Reconnecting to {} in {:?}", exchange_name, delay);
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = time::sleep(delay) => (),
+            }
+        }
+
+        exchange.close().await;
+        output.set_venue_state(&exchange_name, ConnectionState::Disconnected);
+    })
+}
+
+/// Forwards the summaries published on `receiver` to `client`, narrowed down
+/// to the client's subscription options, until the client goes away, the
+/// pipeline stops or the client is told to stop. The pipeline is never made
+/// to wait for the client: updates the client is too slow to receive are
+/// conflated according to its policy and counted as dropped. At most one
+/// update is sent per minimum interval of the client, and only the latest
+/// update of an interval is kept, without counting the others as dropped
+/// since the client asked not to see them. The client is then removed from
+/// `clients` and its subscription released.
+pub async fn client_worker(
+    client: Client,
+    mut receiver: broadcast::Receiver<Summary>,
+    clients: Arc<Mutex<ClientRegistry>>,
+    pipelines: PipelineManager,
+) {
+    let mut queue: VecDeque<Summary> = VecDeque::new();
+    // The latest update held back until the minimum interval has passed.
+    let mut pending: Option<Summary> = None;
+    let mut next_send = Instant::now();
+
+    loop {
+        let is_due = Instant::now() >= next_send;
+        if is_due {
+            if let Some(summary) = pending.take() {
+                client.record_dropped(client.options.conflate(&mut queue, summary));
+            }
+        }
+        let is_waiting = pending.is_some() || !queue.is_empty();
+
+        tokio::select! {
+            biased;
+            _ = client.sender.closed() => break,
+            _ = client.stop.notified() => break,
+            _ = time::sleep_until(next_send), if is_waiting && !is_due => (),
+            permit = client.sender.reserve(), if !queue.is_empty() && is_due => {
+                match (permit, queue.pop_front()) {
+                    (Ok(permit), Some(summary)) => permit.send(Ok(summary)),
+                    _ => break,
+                }
+                next_send = Instant::now() + client.options.min_interval;
+            }
+            summary = receiver.recv() => match summary {
+                Ok(summary) => {
+                    let summary = client.options.apply(&summary);
+                    if is_due {
+                        client.record_dropped(client.options.conflate(&mut queue, summary));
+                    }
+                    else {
+                        pending = Some(summary);
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    client.record_dropped(skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+        }
     }
+
+    clients.lock().await.unregister(client.id);
+    pipelines.unsubscribe(&client.symbol).await;
+    println!("Client {} unsubscribed from {}", client.id, client.symbol);
 }
 
+#[tokio::test(start_paused = true)]
+async fn test_client_worker_throttles_without_counting_drops() {
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use crate::client::{Conflation, SubscriptionOptions};
+    use crate::orderbook_aggregator::Level;
+
+    let config = Arc::new(Config {
+        exchanges: Vec::new(),
+        ..Config::default()
+    });
+    let venues = Arc::new(VenueRegistry::from_config(&config).unwrap());
+    let pipelines = PipelineManager::new(config, venues);
+    let clients = Arc::new(Mutex::new(ClientRegistry::default()));
+
+    let options = SubscriptionOptions {
+        depth: 10,
+        exchanges: Vec::new(),
+        min_interval: Duration::from_millis(1000),
+        conflation: Conflation::Latest,
+    };
+    let (sender, mut receiver) = mpsc::channel(1);
+    let client = clients.lock().await.register(String::from("btcusdt"), options, sender);
+    let (summaries, _) = broadcast::channel(16);
+    tokio::spawn(client_worker(client.clone(), summaries.subscribe(), clients, pipelines));
+
+    let summary = |price: f64| Summary {
+        spread: 0.0,
+        bids: vec![Level { exchange: String::from("Binance"), price, amount: 1.0 }],
+        asks: Vec::new(),
+        symbol: String::from("btcusdt"),
+    };
+    let received = |summary: Option<Result<Summary, tonic::Status>>| -> f64 {
+        summary.unwrap().unwrap().bids[0].price
+    };
+    let settle = || time::sleep(Duration::from_millis(10));
+
+    // Updates superseded within the interval are not dropped for a client
+    // that keeps up.
+    summaries.send(summary(1.0)).unwrap();
+    assert_eq!(received(receiver.recv().await), 1.0);
+    summaries.send(summary(2.0)).unwrap();
+    summaries.send(summary(3.0)).unwrap();
+    settle().await;
+    assert_eq!(received(receiver.recv().await), 3.0);
+    assert_eq!(client.dropped.load(Ordering::Relaxed), 0);
+
+    // Once the client stops reading, the update waiting for room in its
+    // channel is replaced and counted as dropped.
+    summaries.send(summary(4.0)).unwrap();
+    time::sleep(Duration::from_millis(1500)).await;
+    summaries.send(summary(5.0)).unwrap();
+    time::sleep(Duration::from_millis(1100)).await;
+    summaries.send(summary(6.0)).unwrap();
+    settle().await;
+    assert_eq!(client.dropped.load(Ordering::Relaxed), 1);
+    assert_eq!(received(receiver.recv().await), 4.0);
+    assert_eq!(received(receiver.recv().await), 6.0);
+}